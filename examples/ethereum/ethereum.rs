@@ -1,3 +1,4 @@
+use std::process::ExitCode;
 use std::time::{Duration, SystemTime};
 
 use ethers::prelude::{Block, H256, Middleware, Provider, StreamExt, Ws};
@@ -6,8 +7,9 @@ use eyre::Result;
 use protobuf::well_known_types::timestamp::Timestamp;
 
 use nakji_connector::connector::Connector;
-use nakji_connector::kafka_utils::{Message, MessageType, topic, Topic};
+use nakji_connector::kafka_utils::MessageType;
 use nakji_connector::kafka_utils::key::Key;
+use nakji_connector::sink::SinkError;
 
 use crate::chain::Block as ProtoBlock;
 use crate::chain::chain::Transaction as ProtoTransaction;
@@ -18,42 +20,46 @@ mod chain;
 const WSS_URL: &str = "your rpc url";
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     let block = ProtoBlock::new();
 
     let transaction = ProtoTransaction::new();
-    let mut connector = Connector::new();
-
-    let event_name = topic::get_event_name(Box::new(block.clone()));
-    let topic = Topic::new(
-        connector.config.kafka_env.clone(),
-        MessageType::FCT,
-        connector.manifest.author.clone(),
-        connector.manifest.name.clone(),
-        connector.manifest.version.clone(),
-        event_name,
-    );
+    let connector = Connector::new();
+
     let key = Key::new("ethereum".to_string(), "Block".to_string());
 
     // register protobuf schema
-    connector.register_protos(MessageType::FCT, vec![Box::new(block.clone()), Box::new(transaction.clone())]).await;
+    connector.register_protos(&[MessageType::FCT, MessageType::BF], vec![Box::new(block.clone()), Box::new(transaction.clone())]).await?;
 
     // A ws provider can be created from a ws(s) URI.
     // In case of wss you must add the "rustls" or "openssl" feature
     // to the ethers library dependency in `Cargo.toml`.
     let provider = Provider::<Ws>::connect(WSS_URL).await?;
 
+    // cancelled on SIGTERM or SIGINT, stop reading new blocks
+    let shutdown = connector.shutdown_token();
+
     let mut stream = provider.subscribe_blocks().await?;
-    while let Some(block) = stream.next().await {
+    while let Some(block) = tokio::select! {
+        block = stream.next() => block,
+        _ = shutdown.cancelled() => None,
+    } {
         println!("block hash: {:?}", block.hash.unwrap());
 
-        let b = build_block(&block);
-        let m = Message::new(topic.clone(), key.clone(), b.clone());
-        let messages = vec![m];
-        connector.producer.produce_transactional_messages(messages).await.expect("failed to produce messages");
+        let messages = vec![connector.message(key.clone(), build_block(&block), MessageType::FCT)];
+        match connector.produce(messages).await {
+            Ok(_) => {}
+            // the block is kept by sink.batch or sink.outbox and written with the next batch
+            Err(SinkError::KeptForRetry(err)) if err.is_aborted() => println!("block {:?} is kept for retry: {}", block.number, err),
+            // nothing was committed, this example simply skips the block
+            Err(err) if err.is_aborted() => println!("skipping block {:?}: {}", block.number, err),
+            // e.g. fenced by another instance, also when the block is kept, let the process exit
+            Err(err) => return Err(err.into()),
+        }
     }
 
-    Ok(())
+    // commits the in-flight transaction and writes the blocks still buffered by sink.batch
+    Ok(connector.shutdown().await)
 }
 
 fn build_block(block: &Block<H256>) -> ProtoBlock {
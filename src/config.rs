@@ -1,27 +1,72 @@
 use std::env;
 use std::fs::File;
+use std::time::Duration;
 
 use serde_yaml::Value;
 
-use crate::kafka_utils::Env;
+use crate::kafka_utils::{Env, MessageKeyStrategy, MessageType, ProducerConfig, RecordConfig, SizeLimit, Topic, TopicEncoding};
+use crate::manifest::Manifest;
+use crate::proto_registry::{CompatibilityMode, DESCRIPTOR_CACHE_FILE, LOCAL_REGISTRY_DIR};
+use crate::sink::{BatchConfig, FILE_SINK_PATH, OUTBOX_MAX_BYTES, OUTBOX_PATH, OutboxConfig, SinkConfig};
 
 pub struct Config {
     pub kafka_url: String,
     pub kafka_env: Env,
+    pub producer: ProducerConfig,
     pub proto_registry_host: String,
+    pub proto_registry_compatibility: CompatibilityMode,
+    // the protoregistry service answers GET /v1/descriptor, needed to check the compatibility of changed schemas
+    pub proto_registry_descriptor_lookup: bool,
+    pub proto_registry_path: String,
+    pub proto_registry_cache_path: String,
+    pub sink: SinkConfig,
+    pub outbox: Option<OutboxConfig>,
+    pub batch: Option<BatchConfig>,
     pub sub_config: Value,
 }
 
 const CONFIG_KEY_KAFKA: &str = "kafka";
 const CONFIG_KEY_KAFKA_URL: &str = "url";
 const CONFIG_KEY_KAFKA_ENV: &str = "env";
+const CONFIG_KEY_KAFKA_PRODUCER: &str = "producer";
+const CONFIG_KEY_KAFKA_PRODUCER_MODE: &str = "mode";
+const CONFIG_KEY_KAFKA_PRODUCER_EVENT_TIME: &str = "event_time";
+const CONFIG_KEY_KAFKA_PRODUCER_ENCODING: &str = "encoding";
+const CONFIG_KEY_KAFKA_PRODUCER_TOPIC_ENCODING: &str = "topic_encoding";
+const CONFIG_KEY_KAFKA_PRODUCER_MAX_MESSAGE_BYTES: &str = "max_message_bytes";
+const CONFIG_KEY_KAFKA_PRODUCER_OVERSIZED: &str = "oversized";
+const CONFIG_KEY_KAFKA_PRODUCER_KEY_STRATEGY: &str = "key_strategy";
+const CONFIG_KEY_KAFKA_PRODUCER_MESSAGE_KEY_STRATEGY: &str = "message_key_strategy";
+const CONFIG_KEY_KAFKA_PRODUCER_DEAD_LETTER: &str = "dead_letter";
+const CONFIG_KEY_KAFKA_PRODUCER_DEAD_LETTER_ENABLED: &str = "enabled";
+const CONFIG_KEY_KAFKA_PRODUCER_DEAD_LETTER_TOPIC: &str = "topic";
 const CONFIG_KEY_PROTO_REGISTRY: &str = "protoregistry";
 const CONFIG_KEY_PROTO_REGISTRY_HOST: &str = "host";
+const CONFIG_KEY_PROTO_REGISTRY_COMPATIBILITY: &str = "compatibility";
+const CONFIG_KEY_PROTO_REGISTRY_DESCRIPTOR_LOOKUP: &str = "descriptor_lookup";
+const CONFIG_KEY_PROTO_REGISTRY_PATH: &str = "path";
+const CONFIG_KEY_PROTO_REGISTRY_CACHE_PATH: &str = "cache_path";
+const CONFIG_KEY_SINK: &str = "sink";
+const CONFIG_KEY_SINK_TYPE: &str = "type";
+const CONFIG_KEY_SINK_PATH: &str = "path";
+const CONFIG_KEY_SINK_OUTBOX: &str = "outbox";
+const CONFIG_KEY_SINK_OUTBOX_PATH: &str = "path";
+const CONFIG_KEY_SINK_OUTBOX_MAX_BYTES: &str = "max_bytes";
+const CONFIG_KEY_SINK_BATCH: &str = "batch";
+const CONFIG_KEY_SINK_BATCH_MAX_MESSAGES: &str = "max_messages";
+const CONFIG_KEY_SINK_BATCH_MAX_BYTES: &str = "max_bytes";
+const CONFIG_KEY_SINK_BATCH_MAX_DELAY_MS: &str = "max_delay_ms";
+const CONFIG_KEY_SINK_BATCH_MAX_BUFFERED_BYTES: &str = "max_buffered_bytes";
+
+const DEAD_LETTER_EVENT_NAME: &str = "dlq";
 
 impl Config {
-    pub fn init() -> Self {
-        let yaml = Config::read_from_file();
+    /// The manifest names the dead letter topic, when it is enabled without a topic.
+    pub fn init(manifest: &Manifest) -> Self {
+        Config::from_yaml(&Config::read_from_file(), manifest)
+    }
 
+    pub fn from_yaml(yaml: &Value, manifest: &Manifest) -> Self {
         let kafka_url = yaml[CONFIG_KEY_KAFKA][CONFIG_KEY_KAFKA_URL]
             .as_str()
             .expect("kafka url should be a string")
@@ -31,19 +76,159 @@ impl Config {
             .expect("kafka env should be a string")
             .parse()
             .expect("wrong env value");
+        let producer_yaml = &yaml[CONFIG_KEY_KAFKA][CONFIG_KEY_KAFKA_PRODUCER];
+        let dead_letter_yaml = &producer_yaml[CONFIG_KEY_KAFKA_PRODUCER_DEAD_LETTER];
+        let dead_letter_enabled = dead_letter_yaml[CONFIG_KEY_KAFKA_PRODUCER_DEAD_LETTER_ENABLED]
+            .as_bool()
+            .unwrap_or_default();
+        let producer = ProducerConfig {
+            mode: producer_yaml[CONFIG_KEY_KAFKA_PRODUCER_MODE]
+                .as_str()
+                .map(|s| s.parse().expect("wrong producer mode value"))
+                .unwrap_or_default(),
+            event_time: producer_yaml[CONFIG_KEY_KAFKA_PRODUCER_EVENT_TIME]
+                .as_bool()
+                .unwrap_or_default(),
+            dead_letter_topic: match dead_letter_yaml[CONFIG_KEY_KAFKA_PRODUCER_DEAD_LETTER_TOPIC].as_str() {
+                _ if !dead_letter_enabled => None,
+                Some(topic) => Some(topic.to_string()),
+                None => Some(Config::dead_letter_topic(manifest, &kafka_env).to_string()),
+            },
+            size_limit: SizeLimit {
+                max_bytes: producer_yaml[CONFIG_KEY_KAFKA_PRODUCER_MAX_MESSAGE_BYTES]
+                    .as_u64()
+                    .map(|n| n as usize)
+                    .unwrap_or(SizeLimit::default().max_bytes),
+                policy: producer_yaml[CONFIG_KEY_KAFKA_PRODUCER_OVERSIZED]
+                    .as_str()
+                    .map(|s| s.parse().expect("wrong oversized policy value"))
+                    .unwrap_or_default(),
+            },
+            record: RecordConfig {
+                encoding: TopicEncoding {
+                    default: producer_yaml[CONFIG_KEY_KAFKA_PRODUCER_ENCODING]
+                        .as_str()
+                        .map(|s| s.parse().expect("wrong encoding value"))
+                        .unwrap_or_default(),
+                    topics: producer_yaml[CONFIG_KEY_KAFKA_PRODUCER_TOPIC_ENCODING]
+                        .as_mapping()
+                        .map(|topics| topics.iter()
+                            .map(|(topic, encoding)| (
+                                topic.as_str().expect("topic should be a string").to_string(),
+                                encoding.as_str().and_then(|s| s.parse().ok()).expect("wrong encoding value"),
+                            ))
+                            .collect())
+                        .unwrap_or_default(),
+                },
+                key_strategy: MessageKeyStrategy {
+                    default: producer_yaml[CONFIG_KEY_KAFKA_PRODUCER_KEY_STRATEGY]
+                        .as_str()
+                        .map(|s| s.parse().expect("wrong key strategy value"))
+                        .unwrap_or_default(),
+                    messages: producer_yaml[CONFIG_KEY_KAFKA_PRODUCER_MESSAGE_KEY_STRATEGY]
+                        .as_mapping()
+                        .map(|messages| messages.iter()
+                            .map(|(message, strategy)| (
+                                message.as_str().expect("message name should be a string").to_string(),
+                                strategy.as_str().and_then(|s| s.parse().ok()).expect("wrong key strategy value"),
+                            ))
+                            .collect())
+                        .unwrap_or_default(),
+                },
+            },
+        };
         let proto_registry_host = yaml[CONFIG_KEY_PROTO_REGISTRY][CONFIG_KEY_PROTO_REGISTRY_HOST]
             .as_str()
             .expect("proto registry host should be a string")
             .to_string();
+        let proto_registry_compatibility: CompatibilityMode = yaml[CONFIG_KEY_PROTO_REGISTRY][CONFIG_KEY_PROTO_REGISTRY_COMPATIBILITY]
+            .as_str()
+            .map(|s| s.parse().expect("wrong compatibility value"))
+            .unwrap_or_default();
+        let proto_registry_descriptor_lookup = yaml[CONFIG_KEY_PROTO_REGISTRY][CONFIG_KEY_PROTO_REGISTRY_DESCRIPTOR_LOOKUP]
+            .as_bool()
+            .unwrap_or_default();
+        let proto_registry_path = yaml[CONFIG_KEY_PROTO_REGISTRY][CONFIG_KEY_PROTO_REGISTRY_PATH]
+            .as_str()
+            .unwrap_or(LOCAL_REGISTRY_DIR)
+            .to_string();
+        let proto_registry_cache_path = yaml[CONFIG_KEY_PROTO_REGISTRY][CONFIG_KEY_PROTO_REGISTRY_CACHE_PATH]
+            .as_str()
+            .unwrap_or(DESCRIPTOR_CACHE_FILE)
+            .to_string();
+        let sink = match yaml[CONFIG_KEY_SINK][CONFIG_KEY_SINK_TYPE].as_str() {
+            None | Some("kafka") => SinkConfig::Kafka,
+            Some("stdout") => SinkConfig::Stdout,
+            Some("file") => SinkConfig::File(yaml[CONFIG_KEY_SINK][CONFIG_KEY_SINK_PATH]
+                .as_str()
+                .unwrap_or(FILE_SINK_PATH)
+                .into()),
+            Some(other) => panic!("wrong sink type value: {}", other),
+        };
+        let outbox_yaml = &yaml[CONFIG_KEY_SINK][CONFIG_KEY_SINK_OUTBOX];
+        let outbox = outbox_yaml.as_mapping().map(|_| OutboxConfig {
+            path: outbox_yaml[CONFIG_KEY_SINK_OUTBOX_PATH]
+                .as_str()
+                .unwrap_or(OUTBOX_PATH)
+                .into(),
+            max_bytes: outbox_yaml[CONFIG_KEY_SINK_OUTBOX_MAX_BYTES]
+                .as_u64()
+                .unwrap_or(OUTBOX_MAX_BYTES),
+        });
+        let batch_yaml = &yaml[CONFIG_KEY_SINK][CONFIG_KEY_SINK_BATCH];
+        let batch = batch_yaml.as_mapping().map(|_| {
+            let default = BatchConfig::default();
+            BatchConfig {
+                max_messages: batch_yaml[CONFIG_KEY_SINK_BATCH_MAX_MESSAGES]
+                    .as_u64()
+                    .map(|n| n as usize)
+                    .unwrap_or(default.max_messages),
+                max_bytes: batch_yaml[CONFIG_KEY_SINK_BATCH_MAX_BYTES]
+                    .as_u64()
+                    .map(|n| n as usize)
+                    .unwrap_or(default.max_bytes),
+                max_delay: batch_yaml[CONFIG_KEY_SINK_BATCH_MAX_DELAY_MS]
+                    .as_u64()
+                    .map(|ms| match ms {
+                        0 => panic!("batch max_delay_ms should be greater than 0"),
+                        ms => Duration::from_millis(ms),
+                    })
+                    .unwrap_or(default.max_delay),
+                max_buffered_bytes: batch_yaml[CONFIG_KEY_SINK_BATCH_MAX_BUFFERED_BYTES]
+                    .as_u64()
+                    .map(|n| n as usize)
+                    .unwrap_or(default.max_buffered_bytes),
+            }
+        });
 
         Config {
             kafka_url,
             kafka_env,
+            producer,
             proto_registry_host,
+            proto_registry_compatibility,
+            proto_registry_descriptor_lookup,
+            proto_registry_path,
+            proto_registry_cache_path,
+            sink,
+            outbox,
+            batch,
             sub_config: Value::Null,
         }
     }
 
+    // e.g. prod.sys.nakji.ethereum.0_1_0.dlq
+    fn dead_letter_topic(manifest: &Manifest, kafka_env: &Env) -> Topic {
+        Topic::new(
+            kafka_env.clone(),
+            MessageType::SYS,
+            manifest.author.clone(),
+            manifest.name.clone(),
+            manifest.version.clone(),
+            DEAD_LETTER_EVENT_NAME.to_string(),
+        )
+    }
+
     fn read_from_file() -> Value {
         let config_file_name = "config.yaml";
         let config_path = env::var("CONFIGPATH").unwrap_or_else(|_| "$HOME/.config".to_string());
@@ -1,32 +1,84 @@
+use std::any::TypeId;
 use std::collections::HashMap;
+use std::process::ExitCode;
+use std::sync::{Arc, RwLock};
 
-use log::debug;
-use protobuf::MessageDyn;
+use log::{debug, error, info};
+use protobuf::{MessageDyn, MessageFull};
+use tokio_util::sync::CancellationToken;
 
-use crate::proto_registry;
+use crate::proto_registry::{self, HttpProtoRegistry, LocalProtoRegistry, ProtoRegistry, ProtoRegistryError, RegistrationReport};
 use crate::config::Config;
-use crate::kafka_utils::{Env, MessageType, Producer, Topic, topic};
+use crate::kafka_utils::{Env, IntoRecord, MessageType, Partitioner, Producer, Topic, TypedMessage, topic};
+use crate::kafka_utils::key::Key;
 use crate::manifest::Manifest;
+use crate::shutdown::{self, SHUTDOWN_ABORT_TIMEOUT, SHUTDOWN_TIMEOUT};
+use crate::sink::{BatchingSink, FileSink, KafkaSink, OutboxSink, Sink, SinkConfig, SinkError, StdoutSink};
 
 pub struct Connector {
-    pub producer: Producer,
+    /// Backend of `produce`, selected with `sink.type`. It replaces the former `producer` field, the Kafka producer
+    /// is available with `Connector::producer`.
+    pub sink: Box<dyn Sink>,
     pub config: Config,
     pub manifest: Manifest,
+    pub proto_registry: Box<dyn ProtoRegistry>,
+    // (message type, protobuf message type) -> topic
+    topics: RwLock<HashMap<(MessageType, TypeId), Topic>>,
+    // handle to the producer of the kafka sink, which is wrapped by the outbox and batching sinks
+    producer: Option<Producer>,
+    outbox: Option<Arc<OutboxSink>>,
+    shutdown: CancellationToken,
 }
 
 impl Connector {
     pub fn new() -> Self {
-        let mut config = Config::init();
         let manifest = Manifest::init();
+        let mut config = Config::init(&manifest);
+        config.build_sub_config(Connector::id(&manifest, &config).as_str());
+
+        Connector::with_config(config, manifest)
+    }
+
+    pub(crate) fn with_config(config: Config, manifest: Manifest) -> Self {
         let id = Connector::id(&manifest, &config);
-        let producer = Producer::new(&config.kafka_url, &id);
+        let producer = match &config.sink {
+            SinkConfig::Kafka => Some(Producer::new(&config.kafka_url, &id, config.producer.clone())),
+            _ => None,
+        };
+        let sink: Box<dyn Sink> = match (&producer, &config.sink) {
+            (Some(producer), _) => Box::new(KafkaSink::new(producer.clone())),
+            (None, SinkConfig::File(path)) => Box::new(FileSink::open(path).expect("failed to open sink file")),
+            (None, _) => Box::new(StdoutSink::new()),
+        };
+        let (sink, outbox): (Box<dyn Sink>, _) = match &config.outbox {
+            Some(outbox) => {
+                let outbox = Arc::new(OutboxSink::open(&outbox.path, outbox.max_bytes, sink).expect("failed to open outbox"));
+                (Box::new(outbox.clone()), Some(outbox))
+            }
+            None => (sink, None),
+        };
+        let sink: Box<dyn Sink> = match &config.batch {
+            Some(batch) => Box::new(BatchingSink::new(batch.clone(), sink)),
+            None => sink,
+        };
+
+        let proto_registry: Box<dyn ProtoRegistry> = match config.kafka_env {
+            Env::Dev => Box::new(LocalProtoRegistry::new(&config.proto_registry_path)),
+            _ => Box::new(HttpProtoRegistry::new(&config.proto_registry_host).with_descriptor_lookup(config.proto_registry_descriptor_lookup)),
+        };
+
+        let shutdown = CancellationToken::new();
+        shutdown::listen(shutdown.clone());
 
-        config.build_sub_config(id.as_str());
-        
         Connector {
-            producer,
+            sink,
             config,
             manifest,
+            proto_registry,
+            topics: RwLock::new(HashMap::new()),
+            producer,
+            outbox,
+            shutdown,
         }
     }
 
@@ -34,28 +86,126 @@ impl Connector {
         format!("{}-{}-{}-{}", manifest.author, manifest.name, manifest.version, config.kafka_env)
     }
 
-    pub async fn register_protos(&self, message_type: MessageType, protobuf_messages: Vec<Box<dyn MessageDyn>>) {
+    /// Registers the schema of every message for each of the message types, in one request to the registry.
+    pub async fn register_protos(&self, message_types: &[MessageType], protobuf_messages: Vec<Box<dyn MessageDyn>>) -> Result<RegistrationReport, ProtoRegistryError> {
         if self.config.kafka_env == Env::Dev {
-            debug!("protoregistry is disabled in dev mode, set kafka.env to other values (e.g., test, staging) to enable it");
-            return;
+            debug!("protoregistry is disabled in dev mode, registering to {} instead", self.proto_registry.location());
         }
 
-        let topic_types = self.build_topic_types(message_type.clone(), protobuf_messages);
+        let topic_types = self.build_topic_types(message_types, protobuf_messages);
+
+        let report = proto_registry::register_dynamic_topics(self.proto_registry.as_ref(), topic_types, self.config.proto_registry_compatibility, self.config.proto_registry_cache_path.as_ref()).await?;
+        self.sink.set_schema_hashes(&report.schema_hashes);
 
-        proto_registry::register_dynamic_topics(&self.config.proto_registry_host, topic_types, message_type).await;
+        Ok(report)
     }
 
-    fn build_topic_types(&self, message_type: MessageType, protobuf_messages: Vec<Box<dyn MessageDyn>>) -> HashMap<String, Box<dyn MessageDyn>> {
-        let mut topic_types: HashMap<String, Box<dyn MessageDyn>> = HashMap::new();
+    // in the order of the message types, then of the messages, without duplicates
+    fn build_topic_types(&self, message_types: &[MessageType], protobuf_messages: Vec<Box<dyn MessageDyn>>) -> Vec<((MessageType, String), Box<dyn MessageDyn>)> {
+        let mut topic_types: Vec<((MessageType, String), Box<dyn MessageDyn>)> = Vec::new();
 
-        for message in protobuf_messages {
-            let topic = self.create_topic_for_dynamic_message(message_type.clone(), message.clone());
-            topic_types.insert(topic.to_schema(), message);
+        for message_type in message_types {
+            for message in &protobuf_messages {
+                let topic = self.create_topic_for_dynamic_message(message_type.clone(), message.clone());
+                let key = (message_type.clone(), topic.to_schema());
+                if !topic_types.iter().any(|(k, _)| *k == key) {
+                    topic_types.push((key, message.clone()));
+                }
+            }
         }
 
         topic_types
     }
 
+    /// Encodes the messages with the configured encoding and writes them to the sink as one batch.
+    /// Messages which cannot be encoded or are too large go to the dead letter topic if it is enabled, see
+    /// `ProducerConfig::dead_letter_topic`.
+    pub async fn produce<M: IntoRecord>(&self, messages: Vec<M>) -> Result<(), SinkError> {
+        let records = self.config.producer.records(messages)?;
+        self.sink.write(records).await
+    }
+
+    /// Writes every buffered batch and waits until it is persisted. Call it before the connector exits,
+    /// otherwise records held by `sink.batch` can be lost.
+    pub async fn flush(&self) -> Result<(), SinkError> {
+        self.sink.flush().await
+    }
+
+    /// The Kafka producer when `sink.type` is kafka, e.g. for `Producer::send_message`. Records produced through it
+    /// directly skip the outbox and batching sinks.
+    pub fn producer(&self) -> Option<&Producer> {
+        self.producer.as_ref()
+    }
+
+    /// The outbox when `sink.outbox` is set, e.g. for `OutboxSink::metrics`.
+    pub fn outbox(&self) -> Option<&OutboxSink> {
+        self.outbox.as_deref()
+    }
+
+    /// Recreates the sink after a fatal error, e.g. `ProducerError::Fenced` or `ProducerError::AbortFailed`.
+    pub async fn reinitialize(&self) -> Result<(), SinkError> {
+        self.sink.reinitialize().await
+    }
+
+    /// Replaces `HashPartitioner` for the messages partitioned by `kafka.producer.key_strategy`.
+    pub fn set_partitioner(&self, partitioner: Arc<dyn Partitioner>) {
+        self.sink.set_partitioner(partitioner);
+    }
+
+    /// Cancelled on SIGTERM or SIGINT. Sources should stop reading once it is cancelled and call `shutdown`.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Stops the connector: cancels the shutdown token, waits for the in-flight transaction to be committed or
+    /// aborted and flushes the sink. If the flush fails, a transaction left open is aborted. Returns the status code
+    /// the process should exit with.
+    /// A `produce` future dropped while waiting still finishes its transaction in the producer thread.
+    pub async fn shutdown(&self) -> ExitCode {
+        self.shutdown.cancel();
+
+        match tokio::time::timeout(SHUTDOWN_TIMEOUT - SHUTDOWN_ABORT_TIMEOUT, self.flush()).await {
+            Ok(Ok(_)) => {
+                info!("connector shut down");
+                return ExitCode::SUCCESS;
+            }
+            Ok(Err(err)) => error!("failed to flush the sink on shutdown: {}", err),
+            Err(_) => error!("failed to flush the sink within {:?} on shutdown", SHUTDOWN_TIMEOUT - SHUTDOWN_ABORT_TIMEOUT),
+        }
+
+        if let Some(producer) = &self.producer {
+            match tokio::time::timeout(SHUTDOWN_ABORT_TIMEOUT, producer.abort()).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => error!("failed to abort the transaction on shutdown: {}", err),
+                Err(_) => error!("failed to abort the transaction within {:?} on shutdown", SHUTDOWN_ABORT_TIMEOUT),
+            }
+        }
+        ExitCode::FAILURE
+    }
+
+    /// Topic of the protobuf message `T` for this connector, e.g. `prod.fct.nakji.ethereum.0_1_0.evm_Block`.
+    pub fn topic_for<T: MessageFull>(&self, message_type: MessageType) -> Topic {
+        let cache_key = (message_type, TypeId::of::<T>());
+        if let Some(topic) = self.topics.read().unwrap().get(&cache_key) {
+            return topic.clone();
+        }
+
+        let topic = Topic::for_message::<T>(
+            self.config.kafka_env.clone(),
+            cache_key.0.clone(),
+            self.manifest.author.clone(),
+            self.manifest.name.clone(),
+            self.manifest.version.clone(),
+        );
+        self.topics.write().unwrap().insert(cache_key, topic.clone());
+        topic
+    }
+
+    /// Builds a message to produce on the topic of `T`, see `Connector::topic_for`.
+    pub fn message<T: MessageFull>(&self, key: Key, protobuf_message: T, message_type: MessageType) -> TypedMessage<T> {
+        TypedMessage::new(self.topic_for::<T>(message_type), key, protobuf_message)
+    }
+
     pub fn create_topic_for_dynamic_message(&self, message_type: MessageType, message: Box<dyn MessageDyn>) -> Topic {
         let event_name = topic::get_event_name(message);
 
@@ -68,4 +218,135 @@ impl Connector {
             event_name,
         )
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+    use std::path::Path;
+
+    use serde_yaml::Value;
+
+    use crate::kafka_utils::proto_test::{evm, utils};
+    use crate::proto_registry::MockProtoRegistry;
+
+    use super::*;
+
+    fn connector_from_yaml(config: &str) -> Connector {
+        let config: Value = serde_yaml::from_str(config).unwrap();
+        let manifest: Value = serde_yaml::from_str("{name: ethereum, author: nakji, version: 0.1.0}").unwrap();
+        let manifest = Manifest::from_yaml(&manifest);
+
+        Connector::with_config(Config::from_yaml(&config, &manifest), manifest)
+    }
+
+    fn build_connector(registry: &MockProtoRegistry, cache_path: &Path) -> Connector {
+        connector_from_yaml(&format!(
+            "{{kafka: {{url: localhost:9092, env: staging}}, protoregistry: {{host: '{}', descriptor_lookup: true, cache_path: '{}'}}, sink: {{type: stdout}}}}",
+            registry.host(),
+            cache_path.display(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn registers_protos_once() {
+        let cache_path = env::temp_dir().join("nakji-connector-test").join("connector-cache.json");
+        let _ = fs::remove_file(&cache_path);
+        let registry = MockProtoRegistry::start();
+        let connector = build_connector(&registry, &cache_path);
+
+        let report = connector.register_protos(&[MessageType::FCT], vec![Box::new(utils::build_block())]).await.unwrap();
+        assert_eq!(report.new, vec!["fct.nakji.ethereum.0_1_0.evm_Block"]);
+
+        let received = registry.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].topic_name, "nakji.ethereum.0_1_0.evm_Block");
+        assert_eq!(received[0].proto_message_name, "nakji.evm.Block");
+        assert_eq!(received[0].descriptor, proto_registry::descriptor_set(utils::build_block().descriptor_dyn().file_descriptor()));
+
+        let report = connector.register_protos(&[MessageType::FCT], vec![Box::new(utils::build_block())]).await.unwrap();
+        assert_eq!(report.unchanged, vec!["fct.nakji.ethereum.0_1_0.evm_Block"]);
+        assert_eq!(registry.requests().len(), 1);
+        assert!(cache_path.exists());
+
+        let messages: Vec<Box<dyn MessageDyn>> = vec![Box::new(utils::build_transaction()), Box::new(utils::build_block())];
+        let report = connector.register_protos(&[MessageType::FCT, MessageType::BF], messages).await.unwrap();
+        assert_eq!(report.new, vec!["fct.nakji.ethereum.0_1_0.evm_Transaction", "bf.nakji.ethereum.0_1_0.evm_Transaction", "bf.nakji.ethereum.0_1_0.evm_Block"]);
+        assert_eq!(report.unchanged, vec!["fct.nakji.ethereum.0_1_0.evm_Block"]);
+
+        fs::remove_file(&cache_path).expect("failed to remove descriptor cache");
+    }
+
+    #[tokio::test]
+    async fn registers_protos_unchecked_without_descriptor_lookup() {
+        let cache_path = env::temp_dir().join("nakji-connector-test").join("unchecked-cache.json");
+        let _ = fs::remove_file(&cache_path);
+        let registry = MockProtoRegistry::start();
+        let connector = connector_from_yaml(&format!(
+            "{{kafka: {{url: localhost:9092, env: staging}}, protoregistry: {{host: '{}', cache_path: '{}'}}, sink: {{type: stdout}}}}",
+            registry.host(),
+            cache_path.display(),
+        ));
+
+        let report = connector.register_protos(&[MessageType::FCT], vec![Box::new(utils::build_block())]).await.unwrap();
+        assert_eq!(report.unchecked, vec!["fct.nakji.ethereum.0_1_0.evm_Block"]);
+        assert!(report.new.is_empty());
+        assert_eq!(registry.requests().len(), 1);
+
+        fs::remove_file(&cache_path).expect("failed to remove descriptor cache");
+    }
+
+    #[tokio::test]
+    async fn derives_topics_from_message_types() {
+        let registry = MockProtoRegistry::start();
+        let connector = build_connector(&registry, &env::temp_dir().join("nakji-connector-test").join("unused-cache.json"));
+
+        let topic = connector.topic_for::<evm::Block>(MessageType::FCT);
+        assert_eq!(topic.to_string(), "staging.fct.nakji.ethereum.0_1_0.evm_Block");
+        assert_eq!(connector.topic_for::<evm::Block>(MessageType::FCT), topic);
+        assert_eq!(connector.topic_for::<evm::Block>(MessageType::BF).to_string(), "staging.bf.nakji.ethereum.0_1_0.evm_Block");
+        assert_eq!(connector.topic_for::<evm::Transaction>(MessageType::FCT).to_string(), "staging.fct.nakji.ethereum.0_1_0.evm_Transaction");
+
+        let key = Key::new("block".to_string(), "1".to_string());
+        let message = connector.message(key.clone(), utils::build_block(), MessageType::FCT);
+        assert_eq!(message.topic, topic);
+        assert_eq!(message.key, key);
+    }
+
+    #[test]
+    fn derives_dead_letter_topic_from_manifest() {
+        let config = "{kafka: {url: localhost:9092, env: staging, producer: {dead_letter: {enabled: true}}}, protoregistry: {host: unused}, sink: {type: stdout}}";
+        let derived = connector_from_yaml(config);
+        assert_eq!(derived.config.producer.dead_letter_topic.as_deref(), Some("staging.sys.nakji.ethereum.0_1_0.dlq"));
+
+        let named = connector_from_yaml(&config.replace("enabled: true", "enabled: true, topic: dlq"));
+        assert_eq!(named.config.producer.dead_letter_topic.as_deref(), Some("dlq"));
+
+        let disabled = connector_from_yaml(&config.replace("enabled: true", "enabled: false, topic: dlq"));
+        assert_eq!(disabled.config.producer.dead_letter_topic, None);
+    }
+
+    #[tokio::test]
+    async fn exposes_the_kafka_producer_and_outbox() {
+        let registry = MockProtoRegistry::start();
+        let stdout = build_connector(&registry, &env::temp_dir().join("nakji-connector-test").join("unused-cache.json"));
+        assert!(stdout.producer().is_none());
+        assert!(stdout.outbox().is_none());
+        stdout.reinitialize().await.unwrap();
+
+        let outbox_path = env::temp_dir().join("nakji-connector-test").join("connector.outbox.log");
+        let with_outbox = connector_from_yaml(&format!(
+            "{{kafka: {{url: localhost:9092, env: staging}}, protoregistry: {{host: unused}}, sink: {{type: stdout, outbox: {{path: '{}'}}}}}}",
+            outbox_path.display(),
+        ));
+        with_outbox.produce(vec![with_outbox.message(Key::new("block".to_string(), "1".to_string()), utils::build_block(), MessageType::FCT)]).await.unwrap();
+        assert_eq!(with_outbox.outbox().unwrap().metrics().await.replayed_batches, 1);
+
+        let kafka = connector_from_yaml(
+            "{kafka: {url: '127.0.0.1:1', env: staging, producer: {mode: at_most_once}}, protoregistry: {host: unused}, sink: {batch: {max_messages: 10}}}",
+        );
+
+        assert!(kafka.producer().is_some());
+        kafka.reinitialize().await.unwrap();
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+
+use log::warn;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use super::header::{HEADER_CHUNK_COUNT, HEADER_CHUNK_ID, HEADER_CHUNK_INDEX, HEADER_CHUNK_SIZE, Headers};
+use super::record::Record;
+
+// librdkafka default of message.max.bytes
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 1_000_000;
+
+// room left in every record for the headers added by the producer, e.g. the schema hash and connector id, and the
+// record overhead of the Kafka protocol, which are not counted by record_size
+pub const RECORD_OVERHEAD_BYTES: usize = 512;
+
+// room left in every chunk for the chunk headers
+const CHUNK_HEADER_BYTES: usize = 192;
+
+// chunks of one record accepted by default by the Reassembler, 10 GB with chunks of DEFAULT_MAX_MESSAGE_BYTES
+pub const REASSEMBLER_MAX_CHUNKS: usize = 10_000;
+
+/// What to do with a record larger than the size limit, selected with `kafka.producer.oversized` in config.yaml.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum OversizedPolicy {
+    /// Fail the batch with `OversizedError`.
+    #[default]
+    Reject,
+    /// Send the record without payload to the dead letter topic.
+    DeadLetter,
+    /// Split the payload into records with the same key, see `Reassembler`.
+    Chunk,
+}
+
+impl OversizedPolicy {
+    fn as_str(&self) -> &str {
+        match self {
+            OversizedPolicy::Reject => "reject",
+            OversizedPolicy::DeadLetter => "dead_letter",
+            OversizedPolicy::Chunk => "chunk",
+        }
+    }
+}
+
+impl FromStr for OversizedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(OversizedPolicy::Reject),
+            "dead_letter" => Ok(OversizedPolicy::DeadLetter),
+            "chunk" => Ok(OversizedPolicy::Chunk),
+            _ => Err(format!("'{}' is not a valid value for chunk::OversizedPolicy", s)),
+        }
+    }
+}
+
+impl fmt::Display for OversizedPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Largest record the producer sends, also set as `message.max.bytes` of the Kafka producer.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SizeLimit {
+    pub max_bytes: usize,
+    pub policy: OversizedPolicy,
+}
+
+impl Default for SizeLimit {
+    fn default() -> Self {
+        SizeLimit { max_bytes: DEFAULT_MAX_MESSAGE_BYTES, policy: OversizedPolicy::default() }
+    }
+}
+
+impl SizeLimit {
+    /// Largest `record_size` which fits in `max_bytes` once the producer added its headers.
+    pub fn record_bytes(&self) -> usize {
+        self.max_bytes.saturating_sub(RECORD_OVERHEAD_BYTES)
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("message of {size} bytes for the topic {topic} exceeds the limit of {max_bytes} bytes")]
+pub struct OversizedError {
+    pub topic: String,
+    pub size: usize,
+    pub max_bytes: usize,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ReassembleError {
+    #[error("chunk header {0} is missing")]
+    MissingHeader(&'static str),
+    #[error("chunk header {0} is invalid")]
+    InvalidHeader(&'static str),
+}
+
+/// Size of the key, payload and headers of a record, without the headers added by the producer and the protocol
+/// overhead, see `SizeLimit::record_bytes`.
+pub fn record_size(record: &Record) -> usize {
+    record.key.len() + record.payload.len() + record.headers.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>()
+}
+
+/// Applies the policy of the limit to every record larger than `SizeLimit::record_bytes`.
+pub fn apply_size_limit(records: Vec<Record>, limit: &SizeLimit, dead_letter_topic: Option<&str>) -> Result<Vec<Record>, OversizedError> {
+    let mut limited = Vec::with_capacity(records.len());
+    let max_bytes = limit.record_bytes();
+
+    for record in records {
+        let size = record_size(&record);
+        if size <= max_bytes {
+            limited.push(record);
+            continue;
+        }
+
+        let err = OversizedError { topic: record.topic.clone(), size, max_bytes };
+        match (limit.policy, dead_letter_topic) {
+            (OversizedPolicy::Chunk, _) => limited.extend(chunk(record, max_bytes).ok_or(err)?),
+            (OversizedPolicy::DeadLetter, Some(topic)) => {
+                warn!("sending message to the dead letter topic {}: {}", topic, err);
+                limited.push(record.into_dead_letter(topic, &err));
+            }
+            _ => return Err(err),
+        }
+    }
+
+    Ok(limited)
+}
+
+/// Splits the payload of a record into records with a `record_size` of at most `max_bytes`, all with the key and
+/// headers of the original so they are written to the same partition in order. Returns None if the key and headers
+/// alone do not fit.
+pub fn chunk(record: Record, max_bytes: usize) -> Option<Vec<Record>> {
+    let chunk_size = max_bytes.checked_sub(record_size(&record) - record.payload.len() + CHUNK_HEADER_BYTES)
+        .filter(|size| *size > 0)?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(record.topic.as_bytes());
+    hasher.update(&record.key);
+    hasher.update(&record.payload);
+    let id = hex::encode(&hasher.finalize()[..16]);
+
+    let count = record.payload.len().div_ceil(chunk_size);
+    let chunks = record.payload.chunks(chunk_size)
+        .enumerate()
+        .map(|(index, payload)| {
+            let mut headers = record.headers.clone();
+            headers.insert(HEADER_CHUNK_ID, id.as_str());
+            headers.insert(HEADER_CHUNK_INDEX, index.to_string());
+            headers.insert(HEADER_CHUNK_COUNT, count.to_string());
+            headers.insert(HEADER_CHUNK_SIZE, record.payload.len().to_string());
+
+            Record {
+                payload: payload.to_vec(),
+                headers,
+                ..record.clone()
+            }
+        })
+        .collect();
+
+    Some(chunks)
+}
+
+struct PendingChunks {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    // length of the original payload
+    size: usize,
+}
+
+/// Consumer side of `OversizedPolicy::Chunk`: collects the chunks of a record and returns the original payload
+/// once all of them arrived. Records which are not chunked are returned as they are.
+///
+/// The chunks of a record are not sent atomically outside of a transaction: when the producer fails after sending
+/// some of them, the record is never completed. Its chunks are kept until `max_pending` newer records are pending,
+/// or until a chunk with the same id but another count or size starts a new set.
+pub struct Reassembler {
+    pending: HashMap<String, PendingChunks>,
+    // ids in arrival order, the oldest incomplete record is dropped when max_pending is reached
+    order: VecDeque<String>,
+    max_pending: usize,
+    max_chunks: usize,
+}
+
+impl Reassembler {
+    pub fn new(max_pending: usize) -> Self {
+        Reassembler { pending: HashMap::new(), order: VecDeque::new(), max_pending, max_chunks: REASSEMBLER_MAX_CHUNKS }
+    }
+
+    /// Rejects records split into more chunks, `REASSEMBLER_MAX_CHUNKS` by default. The chunk count is read from the
+    /// record headers, so it bounds the memory taken by a malformed record before its chunks arrive.
+    pub fn with_max_chunks(mut self, max_chunks: usize) -> Self {
+        self.max_chunks = max_chunks;
+        self
+    }
+
+    /// Adds a consumed record, returning its full payload when it is complete.
+    pub fn push(&mut self, headers: &Headers, payload: &[u8]) -> Result<Option<Vec<u8>>, ReassembleError> {
+        let id = match headers.get(HEADER_CHUNK_ID) {
+            Some(id) => String::from_utf8_lossy(id).to_string(),
+            None => return Ok(Some(payload.to_vec())),
+        };
+        let index: usize = parse_header(headers, HEADER_CHUNK_INDEX)?;
+        let count: usize = parse_header(headers, HEADER_CHUNK_COUNT)?;
+        let size: usize = parse_header(headers, HEADER_CHUNK_SIZE)?;
+        // every chunk holds at least one byte of the payload
+        if count > size || count > self.max_chunks {
+            return Err(ReassembleError::InvalidHeader(HEADER_CHUNK_COUNT));
+        }
+        if index >= count {
+            return Err(ReassembleError::InvalidHeader(HEADER_CHUNK_INDEX));
+        }
+
+        if let Some(stale) = self.pending.get(&id).filter(|p| p.chunks.len() != count || p.size != size) {
+            warn!("dropping {} chunks of record {}, a chunk with another count or size was received", stale.received, id);
+            self.remove(&id);
+        }
+        if !self.pending.contains_key(&id) {
+            if self.order.len() >= self.max_pending {
+                if let Some(oldest) = self.order.pop_front() {
+                    warn!("dropping incomplete chunked record {}", oldest);
+                    self.pending.remove(&oldest);
+                }
+            }
+            self.order.push_back(id.clone());
+            self.pending.insert(id.clone(), PendingChunks { chunks: vec![None; count], received: 0, size });
+        }
+
+        let pending = self.pending.get_mut(&id).expect("pending chunks are inserted above");
+        // a chunk delivered twice is ignored
+        if pending.chunks[index].is_none() {
+            pending.chunks[index] = Some(payload.to_vec());
+            pending.received += 1;
+        }
+        if pending.received < count {
+            return Ok(None);
+        }
+
+        let pending = self.remove(&id).expect("pending chunks exist");
+        let payload: Vec<u8> = pending.chunks.into_iter().flatten().flatten().collect();
+        if payload.len() != pending.size {
+            return Err(ReassembleError::InvalidHeader(HEADER_CHUNK_SIZE));
+        }
+        Ok(Some(payload))
+    }
+
+    fn remove(&mut self, id: &str) -> Option<PendingChunks> {
+        self.order.retain(|pending_id| pending_id != id);
+        self.pending.remove(id)
+    }
+}
+
+fn parse_header(headers: &Headers, key: &'static str) -> Result<usize, ReassembleError> {
+    let value = headers.get(key).ok_or(ReassembleError::MissingHeader(key))?;
+    std::str::from_utf8(value).ok()
+        .and_then(|v| v.parse().ok())
+        .ok_or(ReassembleError::InvalidHeader(key))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::kafka_utils::proto_test::utils;
+
+    use super::*;
+
+    const DEAD_LETTER_TOPIC: &str = "test.sys.nakji.ethereum.0_1_0.dlq";
+
+    fn build_record(payload_size: usize) -> Record {
+        utils::build_record("ethereum.Block", (0..payload_size).map(|i| i as u8).collect())
+    }
+
+    #[test]
+    fn records_within_limit_are_unchanged() {
+        let limit = SizeLimit { max_bytes: 1000, policy: OversizedPolicy::Reject };
+
+        let records = apply_size_limit(vec![build_record(100)], &limit, None).unwrap();
+
+        assert_eq!(records, vec![build_record(100)]);
+    }
+
+    #[test]
+    fn oversized_records_follow_policy() {
+        let mut limit = SizeLimit { max_bytes: 1000, policy: OversizedPolicy::Reject };
+        let err = apply_size_limit(vec![build_record(2000)], &limit, Some(DEAD_LETTER_TOPIC)).unwrap_err();
+        assert_eq!((err.size, err.max_bytes), (2014, 1000 - RECORD_OVERHEAD_BYTES));
+        // room is left for the headers added by the producer
+        assert!(apply_size_limit(vec![build_record(600)], &limit, None).is_err());
+
+        limit.policy = OversizedPolicy::DeadLetter;
+        assert!(apply_size_limit(vec![build_record(2000)], &limit, None).is_err());
+        let records = apply_size_limit(vec![build_record(2000)], &limit, Some(DEAD_LETTER_TOPIC)).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].topic, DEAD_LETTER_TOPIC);
+        assert!(records[0].payload.is_empty());
+
+        limit.policy = OversizedPolicy::Chunk;
+        let records = apply_size_limit(vec![build_record(2000)], &limit, None).unwrap();
+        assert_eq!(records.len(), 8);
+        assert!(records.iter().all(|r| record_size(r) <= limit.record_bytes() && r.key == b"ethereum.Block"));
+    }
+
+    #[test]
+    fn reassemble_chunks_in_any_order() {
+        let original = build_record(2000);
+        let mut chunks = chunk(original.clone(), 1000).unwrap();
+        chunks.reverse();
+        let duplicate = chunks[0].clone();
+        chunks.insert(1, duplicate);
+
+        let mut reassembler = Reassembler::new(10);
+        let mut payloads = Vec::new();
+        for record in chunks {
+            if let Some(payload) = reassembler.push(&record.headers, &record.payload).unwrap() {
+                payloads.push(payload);
+            }
+        }
+
+        assert_eq!(payloads, vec![original.payload]);
+        assert_eq!(reassembler.push(&Headers::new(), b"plain").unwrap(), Some(b"plain".to_vec()));
+    }
+
+    #[test]
+    fn reassembler_drops_oldest_incomplete_record() {
+        let first = chunk(build_record(2000), 1000).unwrap();
+        let second = chunk(build_record(3000), 1000).unwrap();
+        let mut reassembler = Reassembler::new(1);
+
+        assert_eq!(reassembler.push(&first[0].headers, &first[0].payload).unwrap(), None);
+        assert_eq!(reassembler.push(&second[0].headers, &second[0].payload).unwrap(), None);
+        for record in &first[1..] {
+            assert_eq!(reassembler.push(&record.headers, &record.payload).unwrap(), None);
+        }
+
+        let mut headers = first[0].headers.clone();
+        headers.insert(HEADER_CHUNK_INDEX, "9");
+        assert_eq!(reassembler.push(&headers, &[]), Err(ReassembleError::InvalidHeader(HEADER_CHUNK_INDEX)));
+    }
+
+    #[test]
+    fn reassembler_checks_total_size_and_restarts_reused_ids() {
+        let chunks = chunk(build_record(2000), 1000).unwrap();
+        let mut reassembler = Reassembler::new(10);
+
+        // the same id with another count discards the incomplete set
+        let mut reused = chunks[0].headers.clone();
+        reused.insert(HEADER_CHUNK_COUNT, "2");
+        assert_eq!(reassembler.push(&reused, &chunks[0].payload).unwrap(), None);
+        for record in &chunks {
+            assert_eq!(reassembler.push(&record.headers, &record.payload).unwrap().is_some(), record == chunks.last().unwrap());
+        }
+
+        let mut truncated = chunks.clone();
+        truncated.last_mut().unwrap().payload.pop();
+        let results: Vec<_> = truncated.iter().map(|record| reassembler.push(&record.headers, &record.payload)).collect();
+        assert_eq!(results.last().unwrap(), &Err(ReassembleError::InvalidHeader(HEADER_CHUNK_SIZE)));
+    }
+
+    #[test]
+    fn reassembler_rejects_chunk_count_before_allocating() {
+        let chunks = chunk(build_record(2000), 1000).unwrap();
+        let mut reassembler = Reassembler::new(10).with_max_chunks(2);
+
+        let mut huge = chunks[0].headers.clone();
+        huge.insert(HEADER_CHUNK_COUNT, usize::MAX.to_string());
+        assert_eq!(reassembler.push(&huge, &chunks[0].payload), Err(ReassembleError::InvalidHeader(HEADER_CHUNK_COUNT)));
+
+        let mut above_size = chunks[0].headers.clone();
+        above_size.insert(HEADER_CHUNK_COUNT, "2");
+        above_size.insert(HEADER_CHUNK_SIZE, "1");
+        assert_eq!(reassembler.push(&above_size, &chunks[0].payload), Err(ReassembleError::InvalidHeader(HEADER_CHUNK_COUNT)));
+
+        assert!(chunks.len() > 2);
+        assert_eq!(reassembler.push(&chunks[0].headers, &chunks[0].payload), Err(ReassembleError::InvalidHeader(HEADER_CHUNK_COUNT)));
+    }
+}
@@ -0,0 +1,102 @@
+use rdkafka::message::{Header, Headers as KafkaHeaders, OwnedHeaders};
+
+// full name of the protobuf message in the payload, e.g. nakji.evm.Block
+pub const HEADER_PROTO_NAME: &str = "nakji-proto-name";
+// hash of the registered descriptor of the payload, see proto_registry::descriptor_hash
+pub const HEADER_SCHEMA_HASH: &str = "nakji-schema-hash";
+pub const HEADER_CONNECTOR_ID: &str = "nakji-connector-id";
+// milliseconds since the unix epoch when the message was handed to the producer
+pub const HEADER_PRODUCED_AT: &str = "nakji-produced-at";
+// encoding of the payload, see record::Encoding::content_type
+pub const HEADER_CONTENT_TYPE: &str = "content-type";
+// added to dead letter records: the topic the message was meant for, why it was rejected and its encoded size
+pub const HEADER_DLQ_TOPIC: &str = "nakji-dlq-topic";
+pub const HEADER_DLQ_ERROR: &str = "nakji-dlq-error";
+pub const HEADER_DLQ_PAYLOAD_SIZE: &str = "nakji-dlq-payload-size";
+// added to the chunks of an oversized record, see chunk::Reassembler
+pub const HEADER_CHUNK_ID: &str = "nakji-chunk-id";
+pub const HEADER_CHUNK_INDEX: &str = "nakji-chunk-index";
+pub const HEADER_CHUNK_COUNT: &str = "nakji-chunk-count";
+pub const HEADER_CHUNK_SIZE: &str = "nakji-chunk-size";
+
+/// Kafka record headers, kept in insertion order.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Headers(Vec<(String, Vec<u8>)>);
+
+impl Headers {
+    pub fn new() -> Self {
+        Headers(Vec::new())
+    }
+
+    /// Sets a header, replacing any previous value of the same key.
+    pub fn insert(&mut self, key: &str, value: impl Into<Vec<u8>>) {
+        let value = value.into();
+        match self.0.iter_mut().find(|(k, _)| k == key) {
+            Some(header) => header.1 = value,
+            None => self.0.push((key.to_string(), value)),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_slice())
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Copies the headers of a consumed Kafka message, e.g. to pass them to `chunk::Reassembler`.
+    pub fn from_kafka(headers: &impl KafkaHeaders) -> Self {
+        let mut copied = Headers::new();
+        for header in (0..headers.count()).map(|i| headers.get(i)) {
+            copied.insert(header.key, header.value.unwrap_or_default());
+        }
+        copied
+    }
+
+    pub(crate) fn to_owned_headers(&self) -> OwnedHeaders {
+        self.0.iter().fold(OwnedHeaders::new_with_capacity(self.0.len()), |headers, (key, value)| {
+            headers.insert(Header { key, value: Some(value) })
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_replaces_existing_key() {
+        let mut headers = Headers::new();
+        headers.insert(HEADER_PROTO_NAME, "nakji.evm.Block");
+        headers.insert("block", vec![1, 2]);
+        headers.insert(HEADER_PROTO_NAME, "nakji.evm.Transaction");
+
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers.get(HEADER_PROTO_NAME), Some("nakji.evm.Transaction".as_bytes()));
+        assert_eq!(headers.iter().map(|(k, _)| k).collect::<Vec<_>>(), vec![HEADER_PROTO_NAME, "block"]);
+        assert!(!headers.contains(HEADER_SCHEMA_HASH));
+    }
+
+    #[test]
+    fn copy_kafka_headers() {
+        let mut headers = Headers::new();
+        headers.insert(HEADER_CHUNK_ID, "abc");
+        headers.insert(HEADER_CHUNK_INDEX, "1");
+
+        assert_eq!(Headers::from_kafka(&headers.to_owned_headers()), headers);
+    }
+}
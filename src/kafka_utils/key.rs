@@ -1,12 +1,21 @@
 use std::{error, fmt, string::FromUtf8Error};
 
-#[derive(Debug, PartialEq, Clone)]
+/// Key of a Kafka record: `namespace.subject`, optionally followed by the id of the entity, e.g.
+/// `ethereum.Transaction.0xabc`. Dots and backslashes inside a segment are escaped with a backslash, so segments
+/// can hold any bytes. A key with a single dot is read and written unescaped, as before the entity id was added.
+///
+/// Struct literals have to set `entity_id` or end with `..Default::default()`, `Key::new` is the same for both.
+#[derive(Debug, PartialEq, Clone, Default)]
 pub struct Key {
     pub namespace: String,
     pub subject: String,
+    /// Raw bytes of the entity id, e.g. a block number or a contract address. The record is partitioned by it with
+    /// `KeyStrategy::Key`.
+    pub entity_id: Option<Vec<u8>>,
 }
 
 const KEY_DELIMITER: &str = ".";
+const KEY_ESCAPE: u8 = b'\\';
 
 #[derive(Debug, PartialEq)]
 pub enum ParseKeyError {
@@ -17,7 +26,7 @@ pub enum ParseKeyError {
 impl fmt::Display for ParseKeyError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ParseKeyError::WrongFormat(key) => write!(f, "cannot parse key, needs 2 parts separated by {KEY_DELIMITER}: {key}"),
+            ParseKeyError::WrongFormat(key) => write!(f, "cannot parse key, needs 2 or 3 parts separated by {KEY_DELIMITER}: {key}"),
             ParseKeyError::ConvertVec(e) => write!(f, "cannot convert key to String, error: {e}"),
         }
     }
@@ -33,35 +42,90 @@ impl From<FromUtf8Error> for ParseKeyError {
 
 impl Key {
     pub fn new(namespace: String, subject: String) -> Self {
-        Self { namespace, subject }
+        Self { namespace, subject, entity_id: None }
+    }
+
+    /// Appends the id of the entity as third segment of the key.
+    pub fn with_entity_id(mut self, entity_id: impl Into<Vec<u8>>) -> Self {
+        self.entity_id = Some(entity_id.into());
+        self
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        self.to_string().into_bytes()
+        let delimiter = KEY_DELIMITER.as_bytes()[0];
+        if self.entity_id.is_none() && !self.namespace.as_bytes().contains(&delimiter) && !self.subject.as_bytes().contains(&delimiter) {
+            return [self.namespace.as_str(), self.subject.as_str()].join(KEY_DELIMITER).into_bytes();
+        }
+        // an escaped key always has more than one dot, so it is never read as an unescaped one
+        let mut bytes = Vec::new();
+        escape_into(&mut bytes, self.namespace.as_bytes());
+        bytes.extend_from_slice(KEY_DELIMITER.as_bytes());
+        escape_into(&mut bytes, self.subject.as_bytes());
+        if let Some(entity_id) = &self.entity_id {
+            bytes.extend_from_slice(KEY_DELIMITER.as_bytes());
+            escape_into(&mut bytes, entity_id);
+        }
+        bytes
     }
 
     pub fn parse_key(key: &Vec<u8>) -> Result<Self, ParseKeyError> {
         if key.is_empty() {
-            return Ok(Self { namespace: Default::default(), subject: Default::default() });
+            return Ok(Self::new(Default::default(), Default::default()));
         }
-        let key_string = String::from_utf8(key.clone())?;
-        let key_list: Vec<_> = key_string.split(KEY_DELIMITER).collect();
-        if key_list.len() != 2 {
-            return Err(ParseKeyError::WrongFormat(key_string));
+        if key.iter().filter(|byte| **byte == KEY_DELIMITER.as_bytes()[0]).count() == 1 {
+            let key_string = String::from_utf8(key.clone())?;
+            let key_list: Vec<_> = key_string.split(KEY_DELIMITER).collect();
+            return Ok(Self::new(key_list[0].to_string(), key_list[1].to_string()));
+        }
+        let mut segments = split_escaped(key).into_iter();
+        match (segments.next(), segments.next(), segments.next(), segments.next()) {
+            (Some(namespace), Some(subject), entity_id, None) => Ok(Self {
+                namespace: String::from_utf8(namespace)?,
+                subject: String::from_utf8(subject)?,
+                entity_id,
+            }),
+            _ => Err(ParseKeyError::WrongFormat(String::from_utf8(key.clone())?)),
+        }
+    }
+}
+
+fn escape_into(bytes: &mut Vec<u8>, segment: &[u8]) {
+    for byte in segment {
+        if *byte == KEY_ESCAPE || *byte == KEY_DELIMITER.as_bytes()[0] {
+            bytes.push(KEY_ESCAPE);
+        }
+        bytes.push(*byte);
+    }
+}
+
+// a backslash which does not escape a dot or a backslash is kept
+fn split_escaped(key: &[u8]) -> Vec<Vec<u8>> {
+    let delimiter = KEY_DELIMITER.as_bytes()[0];
+    let mut segments = vec![Vec::new()];
+    let mut bytes = key.iter().peekable();
+
+    while let Some(byte) = bytes.next() {
+        let segment = segments.last_mut().expect("there is always a segment");
+        match (*byte, bytes.peek()) {
+            (KEY_ESCAPE, Some(&&next)) if next == KEY_ESCAPE || next == delimiter => {
+                segment.push(next);
+                bytes.next();
+            }
+            (byte, _) if byte == delimiter => segments.push(Vec::new()),
+            (byte, _) => segment.push(byte),
         }
-        Ok(Self { namespace: key_list[0].to_string(), subject: key_list[1].to_string() })
     }
+    segments
 }
 
 impl fmt::Display for Key {
+    /// The escaped key, with invalid UTF-8 of a binary entity id replaced.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let string_list: Vec<&str> = vec![&self.namespace, &self.subject];
-        let s = string_list.join(KEY_DELIMITER);
+        let s = String::from_utf8_lossy(&self.to_bytes()).to_string();
         write!(f, "{s}")
     }
 }
 
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -69,7 +133,7 @@ mod tests {
     #[test]
     fn create_new_key() {
         let key = Key::new("ethereum".to_string(), "Transaction".to_string());
-        let expected = Key { namespace: "ethereum".to_string(), subject: "Transaction".to_string() };
+        let expected = Key { namespace: "ethereum".to_string(), subject: "Transaction".to_string(), ..Default::default() };
         assert_eq!(key, expected);
     }
 
@@ -90,7 +154,7 @@ mod tests {
     #[test]
     fn parse_key_with_bytes() {
         let key: Vec<u8> = vec![101, 116, 104, 101, 114, 101, 117, 109, 46, 84, 114, 97, 110, 115, 97, 99, 116, 105, 111, 110];
-        let expected = Key { namespace: "ethereum".to_string(), subject: "Transaction".to_string() };
+        let expected = Key { namespace: "ethereum".to_string(), subject: "Transaction".to_string(), entity_id: None };
         assert_eq!(Key::parse_key(&key).unwrap(), expected);
     }
 
@@ -103,4 +167,33 @@ mod tests {
         let error_key: Vec<u8> = vec![101, 116, 104, 101, 114, 101, 117, 109, 84, 114, 97, 110, 115, 97, 99, 116, 105, 111, 110];
         assert_eq!(Key::parse_key(&error_key).unwrap_err(), ParseKeyError::WrongFormat("ethereumTransaction".to_string()), "wrong String format error");
     }
+
+    #[test]
+    fn key_with_escaped_segments_and_entity_id() {
+        let key = Key::new("ens".to_string(), "vitalik.eth".to_string()).with_entity_id(vec![0xab, b'.', b'\\', 0xff]);
+
+        let bytes = key.to_bytes();
+        assert_eq!(bytes, b"ens.vitalik\\.eth.\xab\\.\\\\\xff".to_vec());
+        assert_eq!(Key::parse_key(&bytes).unwrap(), key);
+
+        let key = Key::parse_key(&b"ethereum.Transaction.0xabc".to_vec()).unwrap();
+        assert_eq!(key.entity_id, Some(b"0xabc".to_vec()));
+        assert_eq!(key.to_string(), "ethereum.Transaction.0xabc");
+
+        assert!(Key::parse_key(&b"a.b.c.d".to_vec()).is_err());
+    }
+
+    #[test]
+    fn key_with_single_dot_is_not_unescaped() {
+        let key = Key::parse_key(&b"a\\.b".to_vec()).unwrap();
+        assert_eq!(key, Key::new("a\\".to_string(), "b".to_string()));
+        assert_eq!(key.to_bytes(), b"a\\.b".to_vec());
+
+        let key = Key::new("a\\".to_string(), "b".to_string()).with_entity_id("c");
+        assert_eq!(key.to_bytes(), b"a\\\\.b.c".to_vec());
+        assert_eq!(Key::parse_key(&key.to_bytes()).unwrap(), key);
+
+        let key = Key::new("ens".to_string(), "vitalik.eth".to_string());
+        assert_eq!(Key::parse_key(&key.to_bytes()).unwrap(), key);
+    }
 }
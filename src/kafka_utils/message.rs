@@ -1,10 +1,18 @@
-use protobuf::{MessageDyn};
-use super::{key::Key, topic::Topic};
+use protobuf::{MessageDyn, MessageFull};
+use protobuf::reflect::ReflectValueRef;
+use protobuf::well_known_types::timestamp::Timestamp;
+use super::{header::Headers, key::Key, record::{IntoRecord, Record, RecordConfig, RecordError}, topic::Topic};
+
+// every Nakji protobuf message carries its event time in `google.protobuf.Timestamp ts = 1`
+const EVENT_TIME_FIELD: &str = "ts";
 
 pub struct Message {
     pub topic: Topic,
     pub key: Key,
     pub protobuf_message: Box<dyn MessageDyn>,
+    pub headers: Headers,
+    /// Kafka record timestamp in milliseconds since the unix epoch, see `Message::with_timestamp`.
+    pub timestamp: Option<i64>,
 }
 
 impl Message {
@@ -13,10 +21,127 @@ impl Message {
             topic,
             key,
             protobuf_message: Box::new(protobuf_message),
+            headers: Headers::new(),
+            timestamp: None,
+        }
+    }
+
+    /// Adds a Kafka record header, in addition to the ones set by the producer.
+    pub fn with_header(mut self, key: &str, value: impl Into<Vec<u8>>) -> Self {
+        self.headers.insert(key, value);
+        self
+    }
+
+    /// Sets the Kafka record timestamp (milliseconds since the unix epoch), taking precedence over the `ts` field.
+    pub fn with_timestamp(mut self, timestamp: i64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+}
+
+impl IntoRecord for Message {
+    fn into_record(self, config: &RecordConfig) -> Result<Record, RecordError> {
+        build_record(self.topic, self.key, self.protobuf_message.as_ref(), self.headers, self.timestamp, config)
+    }
+}
+
+/// Message of a known protobuf type, produced without boxing the payload.
+pub struct TypedMessage<T: MessageFull> {
+    pub topic: Topic,
+    pub key: Key,
+    pub protobuf_message: T,
+    pub headers: Headers,
+    /// Kafka record timestamp in milliseconds since the unix epoch, see `TypedMessage::with_timestamp`.
+    pub timestamp: Option<i64>,
+}
+
+impl<T: MessageFull> TypedMessage<T> {
+    pub fn new(topic: Topic, key: Key, protobuf_message: T) -> Self {
+        TypedMessage {
+            topic,
+            key,
+            protobuf_message,
+            headers: Headers::new(),
+            timestamp: None,
+        }
+    }
+
+    /// Adds a Kafka record header, in addition to the ones set by the producer.
+    pub fn with_header(mut self, key: &str, value: impl Into<Vec<u8>>) -> Self {
+        self.headers.insert(key, value);
+        self
+    }
+
+    /// Sets the Kafka record timestamp (milliseconds since the unix epoch), taking precedence over the `ts` field.
+    pub fn with_timestamp(mut self, timestamp: i64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+}
+
+impl<T: MessageFull> IntoRecord for TypedMessage<T> {
+    fn into_record(self, config: &RecordConfig) -> Result<Record, RecordError> {
+        build_record(self.topic, self.key, &self.protobuf_message, self.headers, self.timestamp, config)
+    }
+}
+
+impl<T: MessageFull> From<TypedMessage<T>> for Message {
+    fn from(message: TypedMessage<T>) -> Self {
+        Message {
+            topic: message.topic,
+            key: message.key,
+            protobuf_message: Box::new(message.protobuf_message),
+            headers: message.headers,
+            timestamp: message.timestamp,
         }
     }
 }
 
+fn build_record(topic: Topic, key: Key, protobuf_message: &dyn MessageDyn, headers: Headers, timestamp: Option<i64>,
+                config: &RecordConfig) -> Result<Record, RecordError> {
+    let topic = topic.to_string();
+    let encoding = config.encoding.for_topic(&topic);
+    let descriptor = protobuf_message.descriptor_dyn();
+
+    let payload = match encoding.encode(protobuf_message) {
+        Ok(payload) => payload,
+        Err(source) => return Err(RecordError::new(
+            topic,
+            key.to_bytes(),
+            descriptor.full_name().to_string(),
+            headers,
+            protobuf_message.to_string(),
+            source,
+        )),
+    };
+
+    let entity = config.key_strategy.for_message(descriptor.full_name()).entity(&key, protobuf_message);
+    Ok(Record {
+        topic,
+        key: key.to_bytes(),
+        payload,
+        encoding,
+        proto_name: descriptor.full_name().to_string(),
+        descriptor: Some(descriptor),
+        headers,
+        timestamp,
+        event_time: get_event_time(protobuf_message),
+        entity,
+    })
+}
+
+/// Reads the `ts` timestamp field of a protobuf message, in milliseconds since the unix epoch.
+pub fn get_event_time(protobuf_message: &dyn MessageDyn) -> Option<i64> {
+    let field = protobuf_message.descriptor_dyn().field_by_name(EVENT_TIME_FIELD)?;
+    match field.get_singular(protobuf_message)? {
+        ReflectValueRef::Message(m) => {
+            let ts = m.downcast_ref::<Timestamp>()?;
+            Some(ts.seconds * 1000 + i64::from(ts.nanos) / 1_000_000)
+        }
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use semver::Version;
@@ -24,6 +149,8 @@ mod tests {
     use super::super::proto_test::{utils, evm::Block};
     use super::super::topic::*;
     use super::super::key::*;
+    use super::super::header::*;
+    use super::super::record::{Encoding, TopicEncoding};
 
     #[test]
     fn create_new_message() {
@@ -39,20 +166,80 @@ mod tests {
             event_name: "chain_Block".to_string(),
         };
 
-        let key = Key { namespace: "ethereum".to_string(), subject: "Transaction".to_string() };
+        let key = Key::new("ethereum".to_string(), "Transaction".to_string());
 
         let message = Message::new(topic.clone(), key.clone(), eth_block.clone());
         let expected = Message {
             protobuf_message: Box::new(eth_block),
             topic,
             key,
+            headers: Headers::new(),
+            timestamp: None,
         };
 
         assert_eq!(message.topic, expected.topic);
         assert_eq!(message.key, expected.key);
+        assert_eq!(message.headers, expected.headers);
+        assert_eq!(message.timestamp, expected.timestamp);
 
 
 
         assert_eq!(message.protobuf_message.downcast_box::<Block>().unwrap(), expected.protobuf_message.downcast_box::<Block>().unwrap());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn get_event_time_from_protobuf() {
+        let mut eth_block = utils::build_block();
+        assert_eq!(get_event_time(&eth_block), None);
+
+        let mut ts = Timestamp::new();
+        ts.seconds = 1_672_531_200;
+        ts.nanos = 123_456_789;
+        eth_block.ts = protobuf::MessageField::some(ts);
+        assert_eq!(get_event_time(&eth_block), Some(1_672_531_200_123));
+
+        assert_eq!(get_event_time(&Timestamp::new()), None);
+    }
+
+    #[test]
+    fn typed_and_dynamic_messages_have_same_record() {
+        let mut eth_block = utils::build_block();
+        let mut ts = Timestamp::new();
+        ts.seconds = 1_672_531_200;
+        eth_block.ts = protobuf::MessageField::some(ts);
+
+        let topic = Topic::for_message::<Block>(Env::Test, MessageType::FCT, "nakji".to_string(), "ethereum".to_string(), Version::new(0, 1, 0));
+        let key = Key::new("ethereum".to_string(), "Block".to_string());
+
+        let typed = TypedMessage::new(topic.clone(), key.clone(), eth_block.clone()).with_header("trace-id", "abc");
+        let dynamic = Message::new(topic, key, eth_block).with_header("trace-id", "abc");
+
+        let record = typed.into_record(&RecordConfig::default()).unwrap();
+        assert_eq!(record, dynamic.into_record(&RecordConfig::default()).unwrap());
+        assert_eq!(record.topic, "test.fct.nakji.ethereum.0_1_0.evm_Block");
+        assert_eq!(record.proto_name, "nakji.evm.Block");
+        assert_eq!(record.event_time, Some(1_672_531_200_000));
+        assert_eq!(record.timestamp, None);
+        assert_eq!(record.encoding, Encoding::Protobuf);
+        assert_eq!(record.entity, None);
+    }
+
+    #[test]
+    fn encoding_is_selected_by_topic() {
+        let eth_block = utils::build_block();
+        let topic = Topic::for_message::<Block>(Env::Test, MessageType::FCT, "nakji".to_string(), "ethereum".to_string(), Version::new(0, 1, 0));
+        let key = Key::new("ethereum".to_string(), "Block".to_string());
+        let config = RecordConfig {
+            encoding: TopicEncoding {
+                default: Encoding::Protobuf,
+                topics: std::collections::HashMap::from([(topic.to_string(), Encoding::Json)]),
+            },
+            ..Default::default()
+        };
+
+        let record = TypedMessage::new(topic, key, eth_block.clone()).into_record(&config).unwrap();
+
+        assert_eq!(record.encoding, Encoding::Json);
+        assert_eq!(record.payload, protobuf_json_mapping::print_to_string(&eth_block).unwrap().into_bytes());
+    }
+}
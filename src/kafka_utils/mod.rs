@@ -1,10 +1,18 @@
+pub mod chunk;
+pub mod header;
 pub mod message;
+pub mod partition;
 pub mod producer;
+pub mod record;
 pub mod key;
 pub mod topic;
 
 pub(crate) mod proto_test;
 
-pub use message::Message;
-pub use producer::Producer;
+pub use chunk::{OversizedError, OversizedPolicy, Reassembler, SizeLimit};
+pub use header::Headers;
+pub use message::{Message, TypedMessage};
+pub use partition::{HashPartitioner, KeyStrategy, MessageKeyStrategy, Partitioner};
+pub use producer::{Delivery, DeliveryFuture, Producer, ProducerConfig, ProducerError, ProducerMode};
+pub use record::{EncodeError, Encoding, IntoRecord, Record, RecordConfig, RecordError, TopicEncoding};
 pub use topic::{Topic, MessageType, Env, TOPIC_CONTEXT_SEPARATOR, TOPIC_CONTRACT_SEPARATOR};
\ No newline at end of file
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use protobuf::MessageDyn;
+use protobuf::reflect::ReflectValueRef;
+
+use crate::kafka_utils::key::Key;
+
+const KEY_STRATEGY_FIELD_PREFIX: &str = "field:";
+
+/// What decides the partition of a message, selected with `kafka.producer.key_strategy` in config.yaml.
+/// The record key is the same with every strategy.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub enum KeyStrategy {
+    /// The entity id of the key set with `Key::with_entity_id`. librdkafka hashes the record key of keys without
+    /// one, so all messages with the same key are in one partition.
+    #[default]
+    Key,
+    /// The value of a field of the message read by reflection, e.g. `field:number` for the block number or
+    /// `field:address` for a contract, so the load is spread while each entity stays ordered.
+    /// Messages without the field are partitioned as with `Key`.
+    Field(String),
+}
+
+impl KeyStrategy {
+    /// Bytes identifying the entity of the message, hashed by the `Partitioner` instead of the key.
+    pub fn entity(&self, key: &Key, protobuf_message: &dyn MessageDyn) -> Option<Vec<u8>> {
+        let entity_id = || key.entity_id.clone();
+        match self {
+            KeyStrategy::Key => entity_id(),
+            KeyStrategy::Field(name) => field_value(protobuf_message, name).or_else(entity_id),
+        }
+    }
+}
+
+fn field_value(protobuf_message: &dyn MessageDyn, name: &str) -> Option<Vec<u8>> {
+    let field = protobuf_message.descriptor_dyn().field_by_name(name)?;
+    let bytes = match field.get_singular(protobuf_message)? {
+        ReflectValueRef::String(s) => s.as_bytes().to_vec(),
+        ReflectValueRef::Bytes(b) => b.to_vec(),
+        ReflectValueRef::Message(m) => m.write_to_bytes_dyn().ok()?,
+        ReflectValueRef::Enum(_, number) => number.to_string().into_bytes(),
+        value => value.to_string().into_bytes(),
+    };
+    Some(bytes)
+}
+
+impl FromStr for KeyStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "key" => Ok(KeyStrategy::Key),
+            _ => match s.strip_prefix(KEY_STRATEGY_FIELD_PREFIX) {
+                Some(field) if !field.is_empty() => Ok(KeyStrategy::Field(field.to_string())),
+                _ => Err(format!("'{}' is not a valid value for partition::KeyStrategy", s)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for KeyStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyStrategy::Key => write!(f, "key"),
+            KeyStrategy::Field(field) => write!(f, "{}{}", KEY_STRATEGY_FIELD_PREFIX, field),
+        }
+    }
+}
+
+/// Key strategy of the connector, with overrides for single protobuf messages.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct MessageKeyStrategy {
+    pub default: KeyStrategy,
+    // protobuf full name -> key strategy
+    pub messages: HashMap<String, KeyStrategy>,
+}
+
+impl MessageKeyStrategy {
+    pub fn for_message(&self, proto_name: &str) -> &KeyStrategy {
+        self.messages.get(proto_name).unwrap_or(&self.default)
+    }
+}
+
+/// Chooses the partition of records with an entity, see `KeyStrategy`. Set on the connector with
+/// `Connector::set_partitioner` to replace `HashPartitioner`.
+pub trait Partitioner: Send + Sync {
+    /// Returns a partition in `0..partition_count`.
+    fn partition(&self, topic: &str, entity: &[u8], partition_count: i32) -> i32;
+}
+
+/// Murmur2 hash of the entity, the same partitioning as the default partitioner of the Java client.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HashPartitioner;
+
+impl Partitioner for HashPartitioner {
+    fn partition(&self, _topic: &str, entity: &[u8], partition_count: i32) -> i32 {
+        ((murmur2(entity) & 0x7fffffff) % partition_count as u32) as i32
+    }
+}
+
+// murmur2 as implemented by org.apache.kafka.common.utils.Utils
+fn murmur2(data: &[u8]) -> u32 {
+    const SEED: u32 = 0x9747b28c;
+    const M: u32 = 0x5bd1e995;
+    const R: u32 = 24;
+
+    let mut h = SEED ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+
+    let rest = chunks.remainder();
+    if rest.len() >= 3 {
+        h ^= u32::from(rest[2]) << 16;
+    }
+    if rest.len() >= 2 {
+        h ^= u32::from(rest[1]) << 8;
+    }
+    if !rest.is_empty() {
+        h ^= u32::from(rest[0]);
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::kafka_utils::proto_test::utils;
+
+    use super::*;
+
+    #[test]
+    fn entity_is_read_from_field() {
+        let mut eth_block = utils::build_block();
+        eth_block.number = 17_000_000;
+
+        let key = Key::new("ethereum".to_string(), "Block".to_string());
+        assert_eq!(KeyStrategy::Key.entity(&key, &eth_block), None);
+        assert_eq!(KeyStrategy::Field("number".to_string()).entity(&key, &eth_block), Some(b"17000000".to_vec()));
+        assert_eq!(KeyStrategy::Field("missing".to_string()).entity(&key, &eth_block), None);
+
+        let key = key.with_entity_id("0xabc");
+        assert_eq!(KeyStrategy::Key.entity(&key, &eth_block), Some(b"0xabc".to_vec()));
+        assert_eq!(KeyStrategy::Field("number".to_string()).entity(&key, &eth_block), Some(b"17000000".to_vec()));
+        assert_eq!(KeyStrategy::Field("missing".to_string()).entity(&key, &eth_block), Some(b"0xabc".to_vec()));
+
+        assert_eq!("field:number".parse::<KeyStrategy>(), Ok(KeyStrategy::Field("number".to_string())));
+        assert_eq!(KeyStrategy::Field("number".to_string()).to_string(), "field:number");
+        assert!("field:".parse::<KeyStrategy>().is_err());
+    }
+
+    #[test]
+    fn hash_partitioner_matches_java_client() {
+        // values of org.apache.kafka.common.utils.Utils.murmur2
+        assert_eq!(murmur2(b"21") as i32, -973932308);
+        assert_eq!(murmur2(b"foobar") as i32, -790332482);
+        assert_eq!(murmur2(b"a-little-bit-long-string") as i32, -985981536);
+
+        let partition = HashPartitioner.partition("topic", b"foobar", 12);
+        assert_eq!(partition, (-790332482i32 & 0x7fffffff) % 12);
+    }
+}
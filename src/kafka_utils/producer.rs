@@ -1,17 +1,29 @@
-use std::process;
-use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use log::{debug, error, info, warn};
-use protobuf::MessageDyn;
 use rdkafka::{
     ClientConfig,
     error::{KafkaError, RDKafkaErrorCode},
-    producer::{BaseRecord, DefaultProducerContext, Producer as KafkaProducer, ThreadedProducer},
+    producer::{FutureProducer, FutureRecord, Producer as KafkaProducer},
     util::Timeout,
 };
 use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
 
-use super::message::Message;
+use crate::shutdown::{SHUTDOWN_ABORT_TIMEOUT, SHUTDOWN_TIMEOUT};
+
+use super::chunk::{OversizedError, OversizedPolicy, SizeLimit, apply_size_limit, record_size};
+use super::header::{HEADER_CONNECTOR_ID, HEADER_CONTENT_TYPE, HEADER_PRODUCED_AT, HEADER_PROTO_NAME, HEADER_SCHEMA_HASH, Headers};
+use super::partition::{HashPartitioner, Partitioner};
+use super::record::{IntoRecord, Record, RecordConfig, RecordError, into_records};
 
 // the producer will wait for up to the given delay to allow other records to be sent so that the sends can be batched together
 const KAFKA_PRODUCER_LINGER_MS: &str = "1000";
@@ -28,134 +40,842 @@ const KAFKA_PRODUCER_QUEUE_BUFFERING_MAX_MS: &str = "2000";
 const KAFKA_COMPRESSION_CODEC: &str = "snappy";
 
 const KAFKA_INIT_TRANSACTION_TIMEOUT: Timeout = Timeout::After(Duration::from_secs(120));
-const KAFKA_COMMIT_TRANSACTION_TIMEOUT: Timeout = Timeout::After(Duration::from_secs(10));
-const KAFKA_ABORT_TRANSACTION_TIMEOUT: Timeout = Timeout::After(Duration::from_secs(10));
+const KAFKA_COMMIT_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(10);
+const KAFKA_ABORT_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(5);
 const KAFKA_FLUSH_TIMEOUT: Timeout = Timeout::After(Duration::from_secs(15));
+const KAFKA_METADATA_TIMEOUT: Timeout = Timeout::After(Duration::from_secs(10));
+
+// retriable commit errors are retried with exponential backoff, until either limit is reached and the transaction is aborted
+const KAFKA_COMMIT_MAX_RETRIES: u32 = 5;
+const KAFKA_COMMIT_DEADLINE: Duration = Duration::from_secs(10);
+const KAFKA_COMMIT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const KAFKA_COMMIT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+// a transaction being committed when the connector shuts down is committed or aborted before the flush times out
+const _: () = assert!(
+    KAFKA_COMMIT_DEADLINE.as_secs() + KAFKA_ABORT_TRANSACTION_TIMEOUT.as_secs()
+        < SHUTDOWN_TIMEOUT.as_secs() - SHUTDOWN_ABORT_TIMEOUT.as_secs()
+);
+const _: () = assert!(KAFKA_ABORT_TRANSACTION_TIMEOUT.as_secs() <= SHUTDOWN_ABORT_TIMEOUT.as_secs());
+
+// number of transactions which can be queued while another one is being committed
+const PRODUCER_COMMAND_CHANNEL_CAPACITY: usize = 64;
+
+/// Delivery guarantee of the producer, selected with `kafka.producer.mode` in config.yaml.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum ProducerMode {
+    /// Every batch is committed atomically in a Kafka transaction (exactly once).
+    #[default]
+    Transactional,
+    /// Messages are retried without duplicates, but a batch can be partially written (at least once, in order).
+    Idempotent,
+    /// Messages are sent once without waiting for acknowledgements and can be lost.
+    AtMostOnce,
+}
+
+impl ProducerMode {
+    fn as_str(&self) -> &str {
+        match self {
+            ProducerMode::Transactional => "transactional",
+            ProducerMode::Idempotent => "idempotent",
+            ProducerMode::AtMostOnce => "at_most_once",
+        }
+    }
+}
+
+impl FromStr for ProducerMode {
+    type Err = String;
 
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "transactional" => Ok(ProducerMode::Transactional),
+            "idempotent" => Ok(ProducerMode::Idempotent),
+            "at_most_once" => Ok(ProducerMode::AtMostOnce),
+            _ => Err(format!("'{}' is not a valid value for producer::ProducerMode", s)),
+        }
+    }
+}
 
+impl fmt::Display for ProducerMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Settings of the producer, read from `kafka.producer` in config.yaml.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ProducerConfig {
+    pub mode: ProducerMode,
+    /// Use the `ts` field of the protobuf message as the Kafka record timestamp instead of the produce time.
+    pub event_time: bool,
+    pub record: RecordConfig,
+    /// Messages which cannot be encoded, or are rejected as too large before they are sent with
+    /// `OversizedPolicy::DeadLetter`, go to this topic instead of failing the batch. Any other send error, and a
+    /// size rejection by the broker in the delivery report, still fails the batch.
+    pub dead_letter_topic: Option<String>,
+    pub size_limit: SizeLimit,
+}
+
+impl ProducerConfig {
+    /// Encodes the messages and applies the size limit, with the dead letter topic if it is configured.
+    pub fn records<M: IntoRecord>(&self, messages: Vec<M>) -> Result<Vec<Record>, ProducerError> {
+        let records = into_records(messages, &self.record, self.dead_letter_topic.as_deref())?;
+        Ok(apply_size_limit(records, &self.size_limit, self.dead_letter_topic.as_deref())?)
+    }
+}
+
+
+/// Handle to the Kafka producer. It is cheap to clone and can be shared between tasks; transactions from every
+/// clone are queued and committed one at a time by a dedicated thread owning the underlying Kafka producer,
+/// so the blocking librdkafka calls never run on the async runtime.
+#[derive(Clone)]
 pub struct Producer {
-    producer: ThreadedProducer<DefaultProducerContext>,
+    sender: mpsc::Sender<Command>,
+    schema_hashes: SchemaHashes,
+    partitioner: SharedPartitioner,
+    config: Arc<ProducerConfig>,
+}
+
+// protobuf full name -> hash of its registered descriptor
+type SchemaHashes = Arc<RwLock<HashMap<String, String>>>;
+
+type SharedPartitioner = Arc<RwLock<Arc<dyn Partitioner>>>;
+
+enum Command {
+    Produce {
+        records: Vec<Record>,
+        reply: oneshot::Sender<Result<(), ProducerError>>,
+    },
+    SendMessage {
+        records: Vec<Record>,
+        reply: oneshot::Sender<Result<DeliveryFuture, ProducerError>>,
+    },
+    ProduceTransactional {
+        records: Vec<Record>,
+        reply: oneshot::Sender<Result<(), ProducerError>>,
+    },
+    Flush {
+        reply: oneshot::Sender<Result<(), ProducerError>>,
+    },
+    Reinitialize {
+        reply: oneshot::Sender<Result<(), ProducerError>>,
+    },
+    Abort {
+        reply: oneshot::Sender<Result<(), ProducerError>>,
+    },
+}
+
+// owns the Kafka producer, only one transaction can be open at a time
+struct Transactor {
+    connector_id: String,
+    schema_hashes: SchemaHashes,
+    partitioner: SharedPartitioner,
+    // topic -> number of partitions, read from the cluster metadata on the first record with an entity
+    partition_counts: HashMap<String, i32>,
+    config: ProducerConfig,
+    client_config: ClientConfig,
+    producer: FutureProducer,
     transaction_initialized: bool,
+    // a transaction is begun but neither committed nor aborted, e.g. after AbortFailed
+    in_transaction: bool,
+    fenced: bool,
 }
 
 #[derive(Error, Debug)]
 pub enum ProducerError {
-    #[error("failed to marshall protobuf message to bytes")]
-    ConvertBytes(#[from] protobuf::Error),
+    #[error(transparent)]
+    ConvertBytes(#[from] RecordError),
+    #[error(transparent)]
+    Oversized(#[from] OversizedError),
     #[error("producer failed to send message to the topic {0}")]
     Send(String),
+    /// Sending a batch failed in a non-transactional mode after its first message. The messages before the failed one
+    /// are already enqueued, so sending the batch again can duplicate them.
+    #[error("failed to send the batch after {sent} of its messages were enqueued")]
+    PartiallySent {
+        sent: usize,
+        #[source]
+        source: Box<ProducerError>,
+    },
     #[error(transparent)]
     Kafka(#[from] KafkaError),
+    #[error("producer is closed")]
+    Closed,
+    /// Another producer with the same transactional id has started, nothing can be produced until `reinitialize`.
+    #[error("producer is fenced by another instance with the same transactional id")]
+    Fenced,
+    /// The transaction was aborted and none of its messages were committed, the batch can be sent again.
+    #[error("transaction is aborted")]
+    Aborted(#[source] KafkaError),
+    /// The transaction could not be aborted either, the producer has to be reinitialized.
+    #[error("failed to abort transaction")]
+    AbortFailed(#[source] KafkaError),
+    #[error("transactions are not available in {0} mode")]
+    NotTransactional(ProducerMode),
+    #[error("per message delivery is not available in transactional mode, use produce_transactional_messages")]
+    Transactional,
+    /// The partitions of the topic could not be read to partition a record by its entity.
+    #[error("failed to fetch the metadata of the topic {topic}")]
+    Metadata {
+        topic: String,
+        #[source]
+        source: KafkaError,
+    },
+    #[error("failed to deliver message to the topic {topic}")]
+    Delivery {
+        topic: String,
+        #[source]
+        source: KafkaError,
+    },
+}
+
+impl ProducerError {
+    /// Whether the transaction was rolled back, so none of the messages were committed and the batch can be sent again.
+    /// A batch failing after its first message in a non-transactional mode is not aborted, see `ProducerError::PartiallySent`.
+    pub fn is_aborted(&self) -> bool {
+        matches!(
+            self,
+            ProducerError::Aborted(_) | ProducerError::Send(_) | ProducerError::ConvertBytes(_) | ProducerError::Oversized(_) | ProducerError::Metadata { .. }
+        )
+    }
 }
 
 
 impl Producer {
-    pub fn new(kafka_url: &str, transactional_id: &str) -> Self {
-        let producer: ThreadedProducer<_> = ClientConfig::new()
+    /// Creates the Kafka producer and starts the thread committing its transactions.
+    /// The connector id is added to the headers of every message, and used as transactional id in `ProducerMode::Transactional`.
+    pub fn new(kafka_url: &str, connector_id: &str, config: ProducerConfig) -> Self {
+        let mut client_config = ClientConfig::new();
+        client_config
             .set("bootstrap.servers", kafka_url)
-            .set("transactional.id", transactional_id)
             .set("linger.ms", KAFKA_PRODUCER_LINGER_MS)
             .set("request.timeout.ms", KAFKA_PRODUCER_REQUEST_TIMEOUT_MS)
-            .set("transaction.timeout.ms", KAFKA_PRODUCER_TRANSACTION_TIMEOUT_MS)
             .set("queue.buffering.max.ms", KAFKA_PRODUCER_QUEUE_BUFFERING_MAX_MS)
             .set("compression.codec", KAFKA_COMPRESSION_CODEC)
-            .create()
-            .expect("producer creation error");
+            .set("message.max.bytes", config.size_limit.max_bytes.to_string());
+
+        match config.mode {
+            ProducerMode::Transactional => client_config
+                .set("transactional.id", connector_id)
+                .set("transaction.timeout.ms", KAFKA_PRODUCER_TRANSACTION_TIMEOUT_MS),
+            ProducerMode::Idempotent => client_config
+                .set("enable.idempotence", "true"),
+            ProducerMode::AtMostOnce => client_config
+                .set("enable.idempotence", "false")
+                .set("acks", "0")
+                .set("retries", "0"),
+        };
+        let producer: FutureProducer = client_config.create().expect("producer creation error");
+
+        let shared_config = Arc::new(config.clone());
+        let (sender, receiver) = mpsc::channel(PRODUCER_COMMAND_CHANNEL_CAPACITY);
+        let schema_hashes = SchemaHashes::default();
+        let partitioner: SharedPartitioner = Arc::new(RwLock::new(Arc::new(HashPartitioner)));
+        let transactor = Transactor {
+            connector_id: connector_id.to_string(),
+            schema_hashes: schema_hashes.clone(),
+            partitioner: partitioner.clone(),
+            partition_counts: HashMap::new(),
+            config,
+            client_config,
+            producer,
+            transaction_initialized: false,
+            in_transaction: false,
+            fenced: false,
+        };
+        thread::Builder::new()
+            .name("nakji-producer".to_string())
+            .spawn(move || transactor.run(receiver))
+            .expect("failed to spawn producer thread");
+
+        Producer { sender, schema_hashes, partitioner, config: shared_config }
+    }
+
+    /// Settings of the producer, its `record` field turns messages into records with `IntoRecord::into_record`.
+    pub fn config(&self) -> &ProducerConfig {
+        &self.config
+    }
+
+    /// Sets the descriptor hashes sent in the `nakji-schema-hash` header, keyed by protobuf full name.
+    pub fn set_schema_hashes(&self, schema_hashes: &HashMap<String, String>) {
+        self.schema_hashes.write().unwrap().extend(schema_hashes.clone());
+    }
+
+    /// Replaces the partitioner of the records with an entity, `HashPartitioner` by default.
+    pub fn set_partitioner(&self, partitioner: Arc<dyn Partitioner>) {
+        *self.partitioner.write().unwrap() = partitioner;
+    }
+
+    /// Produces the messages with the guarantee of the configured mode: in a single transaction when transactional,
+    /// otherwise each message is only enqueued and delivered in the background.
+    /// Accepts `Message`, `TypedMessage` or, to mix protobuf types in one batch, `Record`.
+    pub async fn produce_messages<M: IntoRecord>(&self, messages: Vec<M>) -> Result<(), ProducerError> {
+        let records = self.config.records(messages)?;
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Produce { records, reply }).await?;
+        response.await.map_err(|_| ProducerError::Closed)?
+    }
+
+    /// Enqueues a single message, returning once it is accepted by the producer queue. The returned future resolves
+    /// when the broker acknowledges the message. Only available in the non-transactional modes.
+    /// A chunked message which fails after some of its chunks were enqueued returns `ProducerError::PartiallySent`.
+    pub async fn send_message<M: IntoRecord>(&self, message: M) -> Result<DeliveryFuture, ProducerError> {
+        // more than one record if the message is chunked
+        let records = self.config.records(vec![message])?;
+        let (reply, response) = oneshot::channel();
+        self.send(Command::SendMessage { records, reply }).await?;
+        response.await.map_err(|_| ProducerError::Closed)?
+    }
+
+    /// Produces all messages in a single Kafka transaction. Concurrent calls are committed in the order they are received.
+    pub async fn produce_transactional_messages<M: IntoRecord>(&self, messages: Vec<M>) -> Result<(), ProducerError> {
+        let records = self.config.records(messages)?;
+        let (reply, response) = oneshot::channel();
+        self.send(Command::ProduceTransactional { records, reply }).await?;
+        response.await.map_err(|_| ProducerError::Closed)?
+    }
+
+    /// Waits until every outstanding message is delivered to Kafka.
+    pub async fn flush(&self) -> Result<(), ProducerError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Flush { reply }).await?;
+        response.await.map_err(|_| ProducerError::Closed)?
+    }
+
+    /// Replaces the Kafka producer with a new one and initializes its transactions, fencing off any other instance
+    /// with the same transactional id. Used to recover after `ProducerError::Fenced` or `ProducerError::AbortFailed`.
+    pub async fn reinitialize(&self) -> Result<(), ProducerError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Reinitialize { reply }).await?;
+        response.await.map_err(|_| ProducerError::Closed)?
+    }
 
-        Producer { producer, transaction_initialized: false }
+    /// Aborts the open transaction, if a failed commit or abort left one. Called by `Connector::shutdown` when the
+    /// flush fails, so the transaction does not stay open until `transaction.timeout.ms`. The transaction being
+    /// committed is finished first.
+    pub async fn abort(&self) -> Result<(), ProducerError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Command::Abort { reply }).await?;
+        response.await.map_err(|_| ProducerError::Closed)?
     }
 
-    // TODO: add mutex?
-    pub async fn produce_transactional_messages(&mut self, messages: Vec<Message>) -> Result<(), ProducerError> {
+    async fn send(&self, command: Command) -> Result<(), ProducerError> {
+        self.sender.send(command).await.map_err(|_| ProducerError::Closed)
+    }
+}
+
+impl Transactor {
+    fn run(mut self, mut receiver: mpsc::Receiver<Command>) {
+        // the caller may have given up waiting on a reply, the command is done either way
+        while let Some(command) = receiver.blocking_recv() {
+            match command {
+                Command::Produce { records, reply } if self.config.mode == ProducerMode::Transactional => {
+                    let _ = reply.send(self.produce_transactional_messages(records));
+                }
+                Command::Produce { records, reply } => {
+                    let _ = reply.send(self.produce_messages(records));
+                }
+                Command::SendMessage { reply, .. } if self.config.mode == ProducerMode::Transactional => {
+                    let _ = reply.send(Err(ProducerError::Transactional));
+                }
+                Command::SendMessage { records, reply } => {
+                    let _ = reply.send(self.send_message(records));
+                }
+                Command::ProduceTransactional { records, reply } => {
+                    let _ = reply.send(self.produce_transactional_messages(records));
+                }
+                Command::Flush { reply } => {
+                    let _ = reply.send(self.producer.flush(KAFKA_FLUSH_TIMEOUT).map_err(ProducerError::from));
+                }
+                Command::Reinitialize { reply } => {
+                    let _ = reply.send(self.reinitialize());
+                }
+                Command::Abort { reply } if self.in_transaction => {
+                    let _ = reply.send(self.abort_transaction());
+                }
+                Command::Abort { reply } => {
+                    let _ = reply.send(Ok(()));
+                }
+            }
+        }
+        debug!("all producer handles are dropped, stopping");
+    }
+
+    fn produce_messages(&mut self, records: Vec<Record>) -> Result<(), ProducerError> {
+        for (sent, record) in records.into_iter().enumerate() {
+            // delivery failures are only logged by librdkafka here, use send_message to observe them
+            if let Err(err) = self.produce_message(record) {
+                return Err(partially_sent(sent, err));
+            }
+        }
+        Ok(())
+    }
+
+    // the records are the chunks of one message, the chunks enqueued before a failure are still delivered
+    fn send_message(&mut self, records: Vec<Record>) -> Result<DeliveryFuture, ProducerError> {
+        let mut pending = VecDeque::with_capacity(records.len());
+        for (sent, record) in records.into_iter().enumerate() {
+            let topic = record.topic.clone();
+            match self.produce_message(record) {
+                Ok(inner) => pending.push_back(pending_delivery(topic, inner)),
+                Err(err) => return Err(partially_sent(sent, err)),
+            }
+        }
+        Ok(DeliveryFuture::new(pending))
+    }
+
+    fn produce_transactional_messages(&mut self, records: Vec<Record>) -> Result<(), ProducerError> {
+        if self.config.mode != ProducerMode::Transactional {
+            return Err(ProducerError::NotTransactional(self.config.mode));
+        }
+
+        if self.fenced {
+            return Err(ProducerError::Fenced);
+        }
+
         if !self.transaction_initialized {
             self.start_producer()?;
             self.transaction_initialized = true;
         }
 
-        self.producer.begin_transaction()?;
-
-        for message in messages {
-            let topic = message.topic;
-            self.produce_message(&topic.to_string(), message.key.to_bytes(), message.protobuf_message)?;
-        }
-
-        'retry: loop {
-            let result = self.producer.commit_transaction(KAFKA_COMMIT_TRANSACTION_TIMEOUT);
-            match result {
-                Ok(_) => { break 'retry; }
-                Err(err) => {
-                    match err {
-                        KafkaError::Transaction(rd_err) if rd_err.is_retriable() => {
-                            warn!("failed to commit transactions, retrying...");
-                            continue;
-                        }
-                        e => {
-                            let rd_err_code = e.rdkafka_error_code();
-                            match rd_err_code {
-                                Some(code) => {
-                                    match code {
-                                        RDKafkaErrorCode::ProducerFenced => {
-                                            error!("producer is fenced");
-                                            self.close();
-                                            break 'retry;
-                                        }
-                                        RDKafkaErrorCode::InvalidTransactionTimeout => {
-                                            warn!("failed to commit transactions, timed out");
-                                            break 'retry;
-                                        }
-                                        _ => {
-                                            error!("failed to commit transactions, aborting...");
-                                            self.producer
-                                                .abort_transaction(KAFKA_ABORT_TRANSACTION_TIMEOUT)
-                                                .expect("failed to abort transaction, killing producer..");
-                                            break 'retry;
-                                        }
-                                    }
-                                }
-                                None => {
-                                    error!("failed to commit transactions, aborting...");
-                                    self.producer
-                                        .abort_transaction(KAFKA_ABORT_TRANSACTION_TIMEOUT)
-                                        .expect("failed to abort transaction, killing producer..");
-                                    break 'retry;
-                                }
-                            }
-                        }
-                    }
-                }
+        if let Err(err) = self.producer.begin_transaction() {
+            return Err(self.transaction_error(err));
+        }
+        self.in_transaction = true;
+
+        for record in records {
+            if let Err(err) = self.produce_message(record) {
+                error!("failed to produce message, aborting transaction: {}", err);
+                self.abort_transaction()?;
+                return Err(err);
             }
         }
 
+        self.commit_transaction()?;
+        self.in_transaction = false;
+
         debug!("successfully committed transactions");
         Ok(())
     }
 
-    fn close(&self) {
-        info!("flushing outstanding Kafka messages");
+    fn commit_transaction(&mut self) -> Result<(), ProducerError> {
+        let mut retry = CommitRetry::new(Instant::now());
 
-        self.producer.flush(KAFKA_FLUSH_TIMEOUT).expect("failed to flush messages");
-        self.producer
-            .abort_transaction(KAFKA_ABORT_TRANSACTION_TIMEOUT)
-            .expect("failed to abort transaction, killing producer..");
-        process::exit(1);
+        loop {
+            let err = match self.producer.commit_transaction(retry.timeout(Instant::now())) {
+                Ok(_) => return Ok(()),
+                Err(err) => err,
+            };
+            if is_fenced(&err) {
+                return Err(self.transaction_error(err));
+            }
+
+            let retriable = matches!(&err, KafkaError::Transaction(rd_err) if rd_err.is_retriable());
+            match retry.next_backoff(retriable, Instant::now()) {
+                Some(backoff) => {
+                    warn!("failed to commit transactions, retrying in {:?} ({}/{})...", backoff, retry.retries, KAFKA_COMMIT_MAX_RETRIES);
+                    thread::sleep(backoff);
+                }
+                None => {
+                    error!("failed to commit transactions, aborting...");
+                    self.abort_transaction()?;
+                    return Err(ProducerError::Aborted(err));
+                }
+            }
+        }
     }
 
-    fn start_producer(&self) -> Result<(), ProducerError> {
-        self.producer.init_transactions(KAFKA_INIT_TRANSACTION_TIMEOUT)?;
+    fn abort_transaction(&mut self) -> Result<(), ProducerError> {
+        match self.producer.abort_transaction(KAFKA_ABORT_TRANSACTION_TIMEOUT) {
+            Ok(_) => {
+                self.in_transaction = false;
+                Ok(())
+            }
+            Err(err) if is_fenced(&err) => Err(self.transaction_error(err)),
+            Err(err) => Err(ProducerError::AbortFailed(err)),
+        }
+    }
+
+    // every transactional call fails once another instance took over the transactional id, nothing can be
+    // produced until the producer is reinitialized
+    fn transaction_error(&mut self, err: KafkaError) -> ProducerError {
+        if !is_fenced(&err) {
+            return ProducerError::Kafka(err);
+        }
+        error!("producer is fenced: {}", err);
+        self.fenced = true;
+        ProducerError::Fenced
+    }
+
+    fn reinitialize(&mut self) -> Result<(), ProducerError> {
+        info!("reinitializing producer");
+
+        if let Err(err) = self.producer.flush(KAFKA_FLUSH_TIMEOUT) {
+            warn!("failed to flush outstanding Kafka messages: {}", err);
+        }
+
+        self.producer = self.client_config.create()?;
+        self.partition_counts.clear();
+        self.transaction_initialized = false;
+        self.in_transaction = false;
+        self.fenced = false;
+
+        if self.config.mode == ProducerMode::Transactional {
+            self.start_producer()?;
+            self.transaction_initialized = true;
+        }
         Ok(())
     }
 
+    fn start_producer(&mut self) -> Result<(), ProducerError> {
+        self.producer
+            .init_transactions(KAFKA_INIT_TRANSACTION_TIMEOUT)
+            .map_err(|err| self.transaction_error(err))
+    }
+
     // TODO: otel metrics and prometheus
-    fn produce_message(&self, topic: &str, key: Vec<u8>, message: Box<dyn MessageDyn>) -> Result<(), ProducerError> {
-        let out_bytes: Vec<u8> = message.write_to_bytes_dyn()?;
+    fn produce_message(&mut self, record: Record) -> Result<rdkafka::producer::DeliveryFuture, ProducerError> {
+        let headers = self.build_headers(&record);
+        let partition = match &record.entity {
+            Some(entity) => Some(self.partition(&record.topic, entity)?),
+            None => None,
+        };
 
-        let base_record = BaseRecord::to(topic)
-            .key(&key)
-            .payload(&out_bytes);
+        let mut future_record = FutureRecord::to(&record.topic)
+            .key(&record.key)
+            .payload(&record.payload)
+            .headers(headers.to_owned_headers());
 
-        if self.producer.send(base_record).is_err() {
-            return Err(ProducerError::Send(topic.to_string()));
+        let timestamp = match record.timestamp {
+            Some(timestamp) => Some(timestamp),
+            None if self.config.event_time => record.event_time,
+            None => None,
+        };
+        if let Some(timestamp) = timestamp {
+            future_record = future_record.timestamp(timestamp);
+        }
+        if let Some(partition) = partition {
+            future_record = future_record.partition(partition);
+        }
+
+        let err = match self.producer.send_result(future_record) {
+            Ok(delivery) => return Ok(delivery),
+            Err((err, _)) => err,
+        };
+
+        let too_large = err.rdkafka_error_code() == Some(RDKafkaErrorCode::MessageSizeTooLarge);
+        match (&self.config.dead_letter_topic, self.config.size_limit.policy) {
+            (Some(dead_letter_topic), OversizedPolicy::DeadLetter) if too_large && &record.topic != dead_letter_topic => {
+                warn!("sending message to the dead letter topic {}: {}", dead_letter_topic, err);
+                self.produce_message(record.into_dead_letter(dead_letter_topic, &err))
+            }
+            // the headers added here took more than RECORD_OVERHEAD_BYTES
+            _ if too_large => {
+                let size = record_size(&record);
+                Err(OversizedError { topic: record.topic, size, max_bytes: self.config.size_limit.record_bytes() }.into())
+            }
+            _ => {
+                error!("failed to send message to the topic {}: {}", record.topic, err);
+                Err(ProducerError::Send(record.topic))
+            }
+        }
+    }
+
+    // falling back to the key would send records of the same entity to another partition, so a failure fails the
+    // send and is not cached, the next record fetches the metadata again
+    fn partition(&mut self, topic: &str, entity: &[u8]) -> Result<i32, ProducerError> {
+        let partition_count = match self.partition_counts.get(topic) {
+            Some(count) => *count,
+            None => {
+                let count = self.fetch_partition_count(topic)
+                    .map_err(|source| ProducerError::Metadata { topic: topic.to_string(), source })?;
+                self.partition_counts.insert(topic.to_string(), count);
+                count
+            }
+        };
+        Ok(self.partitioner.read().unwrap().partition(topic, entity, partition_count))
+    }
+
+    fn fetch_partition_count(&self, topic: &str) -> Result<i32, KafkaError> {
+        let metadata = self.producer.client().fetch_metadata(Some(topic), KAFKA_METADATA_TIMEOUT)?;
+        let unknown = KafkaError::MetadataFetch(RDKafkaErrorCode::UnknownTopicOrPartition);
+        let topic_metadata = metadata.topics().iter().find(|t| t.name() == topic).ok_or_else(|| unknown.clone())?;
+        if let Some(err) = topic_metadata.error() {
+            return Err(KafkaError::MetadataFetch(err.into()));
+        }
+        match topic_metadata.partitions().len() {
+            0 => Err(unknown),
+            count => Ok(count as i32),
+        }
+    }
+
+    // headers set on the message take precedence over the ones added by the producer
+    fn build_headers(&self, record: &Record) -> Headers {
+        let mut headers = record.headers.clone();
+
+        if !headers.contains(HEADER_SCHEMA_HASH) {
+            if let Some(hash) = self.schema_hashes.read().unwrap().get(&record.proto_name) {
+                headers.insert(HEADER_SCHEMA_HASH, hash.as_str());
+            }
+        }
+        if !headers.contains(HEADER_PROTO_NAME) {
+            headers.insert(HEADER_PROTO_NAME, record.proto_name.as_str());
+        }
+        if !headers.contains(HEADER_CONTENT_TYPE) {
+            headers.insert(HEADER_CONTENT_TYPE, record.encoding.content_type());
+        }
+        if !headers.contains(HEADER_CONNECTOR_ID) {
+            headers.insert(HEADER_CONNECTOR_ID, self.connector_id.as_str());
+        }
+        if !headers.contains(HEADER_PRODUCED_AT) {
+            let produced_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+            headers.insert(HEADER_PRODUCED_AT, produced_at.to_string());
+        }
+
+        headers
+    }
+}
+
+// retries of a failed commit, with exponential backoff until the retries or the deadline are exhausted
+struct CommitRetry {
+    retries: u32,
+    backoff: Duration,
+    deadline: Instant,
+}
+
+impl CommitRetry {
+    fn new(now: Instant) -> Self {
+        CommitRetry { retries: 0, backoff: KAFKA_COMMIT_INITIAL_BACKOFF, deadline: now + KAFKA_COMMIT_DEADLINE }
+    }
+
+    // the delay before the next attempt, None when the transaction has to be aborted
+    fn next_backoff(&mut self, retriable: bool, now: Instant) -> Option<Duration> {
+        if !retriable || self.retries >= KAFKA_COMMIT_MAX_RETRIES || now + self.backoff >= self.deadline {
+            return None;
+        }
+
+        let backoff = self.backoff;
+        self.retries += 1;
+        self.backoff = (self.backoff * 2).min(KAFKA_COMMIT_MAX_BACKOFF);
+        Some(backoff)
+    }
+
+    // an attempt does not outlast the deadline
+    fn timeout(&self, now: Instant) -> Duration {
+        KAFKA_COMMIT_TRANSACTION_TIMEOUT.min(self.deadline.saturating_duration_since(now))
+    }
+}
+
+// librdkafka reports the broker error, or its own fatal error once the producer is fenced
+// nothing is enqueued when the first record fails, so the error keeps telling whether the batch can be sent again
+fn partially_sent(sent: usize, err: ProducerError) -> ProducerError {
+    match sent {
+        0 => err,
+        _ => ProducerError::PartiallySent { sent, source: Box::new(err) },
+    }
+}
+
+fn is_fenced(err: &KafkaError) -> bool {
+    matches!(err.rdkafka_error_code(), Some(RDKafkaErrorCode::ProducerFenced | RDKafkaErrorCode::Fenced))
+}
+
+/// Where a message was written.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Delivery {
+    pub partition: i32,
+    pub offset: i64,
+}
+
+/// Resolves with the partition and offset of a message once it is acknowledged by the broker, or with the delivery error.
+/// For a chunked message, it resolves with the first chunk once every chunk is acknowledged.
+pub struct DeliveryFuture {
+    pending: VecDeque<PendingDelivery>,
+    first: Option<Delivery>,
+}
+
+type PendingDelivery = Pin<Box<dyn Future<Output = Result<Delivery, ProducerError>> + Send>>;
+
+fn pending_delivery(topic: String, inner: rdkafka::producer::DeliveryFuture) -> PendingDelivery {
+    Box::pin(async move {
+        match inner.await {
+            Ok(Ok((partition, offset))) => Ok(Delivery { partition, offset }),
+            Ok(Err((source, _))) => Err(ProducerError::Delivery { topic, source }),
+            // the producer was dropped before the delivery report arrived
+            Err(_) => Err(ProducerError::Closed),
+        }
+    })
+}
+
+impl DeliveryFuture {
+    fn new(pending: VecDeque<PendingDelivery>) -> Self {
+        DeliveryFuture { pending, first: None }
+    }
+}
+
+impl Future for DeliveryFuture {
+    type Output = Result<Delivery, ProducerError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        while let Some(inner) = self.pending.front_mut() {
+            let delivery = match inner.as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(result) => result?,
+            };
+            self.pending.pop_front();
+            self.first.get_or_insert(delivery);
+        }
+        Poll::Ready(self.first.ok_or(ProducerError::Closed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use semver::Version;
+
+    use crate::kafka_utils::{Env, MessageType, Topic, TypedMessage};
+    use crate::kafka_utils::key::Key;
+    use crate::kafka_utils::proto_test::{evm, utils};
+
+    use super::*;
+
+    // nothing listens there, records are only enqueued by librdkafka
+    const UNREACHABLE_KAFKA_URL: &str = "127.0.0.1:1";
+
+    fn build_producer(mode: ProducerMode) -> Producer {
+        Producer::new(UNREACHABLE_KAFKA_URL, "nakji-ethereum-0.1.0-test", ProducerConfig { mode, ..Default::default() })
+    }
+
+    fn build_message() -> TypedMessage<evm::Block> {
+        let topic = Topic::for_message::<evm::Block>(Env::Test, MessageType::FCT, "nakji".to_string(), "ethereum".to_string(), Version::new(0, 1, 0));
+        TypedMessage::new(topic, Key::new("block".to_string(), "1".to_string()), utils::build_block())
+    }
+
+    #[tokio::test]
+    async fn abort_without_open_transaction_does_nothing() {
+        let producer = build_producer(ProducerMode::Transactional);
+
+        producer.abort().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn clones_share_one_producer_thread() {
+        let producer = build_producer(ProducerMode::Transactional);
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let producer = producer.clone();
+                tokio::spawn(async move { producer.send_message(build_message()).await })
+            })
+            .collect();
+        for task in tasks {
+            assert!(matches!(task.await.unwrap(), Err(ProducerError::Transactional)));
+        }
+
+        let producer = build_producer(ProducerMode::Idempotent);
+        let result = producer.produce_transactional_messages(vec![build_message()]).await;
+        assert!(matches!(result, Err(ProducerError::NotTransactional(ProducerMode::Idempotent))));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn flush_does_not_block_the_runtime() {
+        let producer = build_producer(ProducerMode::AtMostOnce);
+        producer.produce_messages(vec![build_message()]).await.unwrap();
+
+        // the broker is unreachable, so the producer thread is stuck in flush until KAFKA_FLUSH_TIMEOUT
+        tokio::select! {
+            result = producer.flush() => panic!("flush returned before the timeout: {:?}", result),
+            _ = tokio::time::sleep(Duration::from_millis(100)) => {}
         }
-        Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn fencing_errors_are_detected() {
+        assert!(is_fenced(&KafkaError::Global(RDKafkaErrorCode::ProducerFenced)));
+        assert!(is_fenced(&KafkaError::Global(RDKafkaErrorCode::Fenced)));
+        assert!(!is_fenced(&KafkaError::Global(RDKafkaErrorCode::InvalidTransactionalState)));
+        assert!(!is_fenced(&KafkaError::Canceled));
+    }
+
+    #[test]
+    fn commit_is_retried_with_backoff() {
+        let now = Instant::now();
+        let mut retry = CommitRetry::new(now);
+
+        let backoffs: Vec<_> = std::iter::from_fn(|| retry.next_backoff(true, now)).collect();
+        assert_eq!(backoffs, [100, 200, 400, 800, 1600].map(Duration::from_millis));
+        assert_eq!(retry.retries, KAFKA_COMMIT_MAX_RETRIES);
+
+        let mut retry = CommitRetry { retries: 0, backoff: Duration::from_secs(4), deadline: now + KAFKA_COMMIT_DEADLINE };
+        assert_eq!(retry.next_backoff(true, now), Some(Duration::from_secs(4)));
+        assert_eq!(retry.next_backoff(true, now), Some(KAFKA_COMMIT_MAX_BACKOFF));
+    }
+
+    #[test]
+    fn commit_is_not_retried_past_the_deadline_or_when_not_retriable() {
+        let now = Instant::now();
+
+        assert_eq!(CommitRetry::new(now).next_backoff(false, now), None);
+        assert_eq!(CommitRetry::new(now).next_backoff(true, now + KAFKA_COMMIT_DEADLINE), None);
+
+        let almost = now + KAFKA_COMMIT_DEADLINE - KAFKA_COMMIT_INITIAL_BACKOFF / 2;
+        assert_eq!(CommitRetry::new(now).next_backoff(true, almost), None);
+        assert_eq!(CommitRetry::new(now).timeout(almost), KAFKA_COMMIT_INITIAL_BACKOFF / 2);
+        assert_eq!(CommitRetry::new(now).timeout(now), KAFKA_COMMIT_TRANSACTION_TIMEOUT.min(KAFKA_COMMIT_DEADLINE));
+    }
+
+    #[test]
+    fn parse_producer_mode() {
+        assert_eq!("transactional".parse(), Ok(ProducerMode::Transactional));
+        assert_eq!("idempotent".parse(), Ok(ProducerMode::Idempotent));
+        assert_eq!("at_most_once".parse(), Ok(ProducerMode::AtMostOnce));
+        assert!("exactly_once".parse::<ProducerMode>().is_err());
+        assert_eq!(ProducerMode::AtMostOnce.to_string(), "at_most_once");
+    }
+
+    #[test]
+    fn partially_sent_batch_is_not_aborted() {
+        assert!(ProducerError::Send("topic".to_string()).is_aborted());
+
+        assert!(partially_sent(0, ProducerError::Send("topic".to_string())).is_aborted());
+        let err = partially_sent(1, ProducerError::Send("topic".to_string()));
+        assert!(matches!(err, ProducerError::PartiallySent { sent: 1, .. }));
+        assert!(!err.is_aborted());
+
+        let source = KafkaError::MetadataFetch(RDKafkaErrorCode::UnknownTopicOrPartition);
+        assert!(ProducerError::Metadata { topic: "topic".to_string(), source }.is_aborted());
+    }
+
+    fn fake_delivery() -> (oneshot::Sender<Result<Delivery, ProducerError>>, PendingDelivery) {
+        let (sender, receiver) = oneshot::channel();
+        (sender, Box::pin(async move { receiver.await.unwrap_or(Err(ProducerError::Closed)) }))
+    }
+
+    #[tokio::test]
+    async fn delivery_resolves_with_first_chunk_once_every_chunk_is_acknowledged() {
+        let (first, first_pending) = fake_delivery();
+        let (second, second_pending) = fake_delivery();
+        let mut future = DeliveryFuture::new(VecDeque::from([first_pending, second_pending]));
+
+        second.send(Ok(Delivery { partition: 2, offset: 8 })).unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut future).await.is_err());
+
+        first.send(Ok(Delivery { partition: 2, offset: 7 })).unwrap();
+        assert_eq!(future.await.unwrap(), Delivery { partition: 2, offset: 7 });
+    }
+
+    #[tokio::test]
+    async fn delivery_fails_when_any_chunk_fails() {
+        let (first, first_pending) = fake_delivery();
+        let (second, second_pending) = fake_delivery();
+        let future = DeliveryFuture::new(VecDeque::from([first_pending, second_pending]));
+
+        first.send(Ok(Delivery { partition: 0, offset: 1 })).unwrap();
+        let source = KafkaError::MessageProduction(RDKafkaErrorCode::MessageTimedOut);
+        second.send(Err(ProducerError::Delivery { topic: "topic".to_string(), source })).unwrap();
+        assert!(matches!(future.await, Err(ProducerError::Delivery { .. })));
+
+        let (dropped, pending) = fake_delivery();
+        drop(dropped);
+        assert!(matches!(DeliveryFuture::new(VecDeque::from([pending])).await, Err(ProducerError::Closed)));
+    }
+}
@@ -1,5 +1,10 @@
+use crate::kafka_utils::{Headers, Record};
+
 use super::evm;
 
+#[allow(unused)]
+pub const BLOCK_TOPIC: &str = "test.fct.nakji.ethereum.0_1_0.evm_Block";
+
 #[allow(unused)]
 pub fn build_block() -> evm::Block {
     evm::Block {
@@ -32,4 +37,21 @@ pub fn build_transaction() -> evm::Transaction {
         s: 0,
         special_fields: Default::default(),
     }
-}
\ No newline at end of file
+}
+
+/// Record of a `nakji.evm.Block` on `BLOCK_TOPIC`, without headers or timestamp.
+#[allow(unused)]
+pub fn build_record(key: &str, payload: Vec<u8>) -> Record {
+    Record {
+        topic: BLOCK_TOPIC.to_string(),
+        key: key.as_bytes().to_vec(),
+        payload,
+        encoding: Default::default(),
+        proto_name: "nakji.evm.Block".to_string(),
+        descriptor: None,
+        headers: Headers::new(),
+        timestamp: None,
+        event_time: None,
+        entity: None,
+    }
+}
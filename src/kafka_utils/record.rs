@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::str::FromStr;
+
+use protobuf::MessageDyn;
+use protobuf::reflect::MessageDescriptor;
+use log::warn;
+use thiserror::Error;
+
+use super::header::{HEADER_CONTENT_TYPE, HEADER_DLQ_ERROR, HEADER_DLQ_PAYLOAD_SIZE, HEADER_DLQ_TOPIC, Headers};
+use super::partition::MessageKeyStrategy;
+
+/// Wire format of the record payload, selected with `kafka.producer.encoding` in config.yaml.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Encoding {
+    /// Protobuf binary encoding.
+    #[default]
+    Protobuf,
+    /// Canonical proto3 JSON mapping, for consumers which cannot decode protobuf.
+    Json,
+}
+
+impl Encoding {
+    fn as_str(&self) -> &str {
+        match self {
+            Encoding::Protobuf => "protobuf",
+            Encoding::Json => "json",
+        }
+    }
+
+    /// Value of the `content-type` header of the records.
+    pub fn content_type(&self) -> &str {
+        match self {
+            Encoding::Protobuf => "application/x-protobuf",
+            Encoding::Json => "application/json",
+        }
+    }
+
+    pub fn encode(&self, protobuf_message: &dyn MessageDyn) -> Result<Vec<u8>, EncodeError> {
+        match self {
+            Encoding::Protobuf => Ok(protobuf_message.write_to_bytes_dyn()?),
+            Encoding::Json => Ok(protobuf_json_mapping::print_to_string(protobuf_message)?.into_bytes()),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "protobuf" => Ok(Encoding::Protobuf),
+            "json" => Ok(Encoding::Json),
+            _ => Err(format!("'{}' is not a valid value for record::Encoding", s)),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Encoding of the connector, with overrides for single topics.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct TopicEncoding {
+    pub default: Encoding,
+    // full topic name -> encoding
+    pub topics: HashMap<String, Encoding>,
+}
+
+impl TopicEncoding {
+    pub fn for_topic(&self, topic: &str) -> Encoding {
+        self.topics.get(topic).copied().unwrap_or(self.default)
+    }
+}
+
+/// How messages are turned into records by `IntoRecord`, part of `ProducerConfig`.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct RecordConfig {
+    pub encoding: TopicEncoding,
+    /// Chooses the entity of each message, records with an entity are partitioned by it instead of their key.
+    pub key_strategy: MessageKeyStrategy,
+}
+
+#[derive(Error, Debug)]
+pub enum EncodeError {
+    #[error("failed to marshall protobuf message to bytes")]
+    Protobuf(#[from] protobuf::Error),
+    #[error("failed to print protobuf message as json")]
+    Json(#[from] protobuf_json_mapping::PrintError),
+}
+
+/// A message which could not be encoded. It keeps the text format of the message, so it can still be sent to the
+/// dead letter topic.
+#[derive(Error, Debug)]
+#[error("failed to encode message for the topic {topic}")]
+pub struct RecordError {
+    pub topic: String,
+    #[source]
+    pub source: EncodeError,
+    dead_letter: Box<Record>,
+}
+
+impl RecordError {
+    pub fn new(topic: String, key: Vec<u8>, proto_name: String, mut headers: Headers, text: String, source: EncodeError) -> Self {
+        headers.insert(HEADER_DLQ_TOPIC, topic.as_str());
+        headers.insert(HEADER_DLQ_ERROR, format!("failed to encode message: {}", error_chain(&source)));
+        headers.insert(HEADER_CONTENT_TYPE, "text/plain");
+
+        let dead_letter = Box::new(Record {
+            topic: String::new(),
+            key,
+            payload: text.into_bytes(),
+            encoding: Encoding::default(),
+            proto_name,
+            descriptor: None,
+            headers,
+            timestamp: None,
+            event_time: None,
+            entity: None,
+        });
+        RecordError { topic, source, dead_letter }
+    }
+
+    /// Record for the dead letter topic, with the text format of the message as payload.
+    pub fn into_dead_letter(self, dead_letter_topic: &str) -> Record {
+        Record { topic: dead_letter_topic.to_string(), ..*self.dead_letter }
+    }
+}
+
+/// A message serialized for Kafka. Built from a `Message` or a `TypedMessage` before it is handed to the producer,
+/// so messages of different protobuf types can be produced in the same batch.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Record {
+    pub topic: String,
+    pub key: Vec<u8>,
+    pub payload: Vec<u8>,
+    pub encoding: Encoding,
+    // full name of the protobuf message in the payload, e.g. nakji.evm.Block
+    pub proto_name: String,
+    // lets sinks decode the payload again, e.g. to print it, None for records built by hand
+    pub descriptor: Option<MessageDescriptor>,
+    pub headers: Headers,
+    /// Explicit Kafka record timestamp in milliseconds since the unix epoch.
+    pub timestamp: Option<i64>,
+    /// Value of the `ts` field of the message, used as record timestamp when `kafka.producer.event_time` is enabled.
+    pub event_time: Option<i64>,
+    /// Entity of the message given by its `KeyStrategy`, e.g. the entity id of its key. The partition is chosen from
+    /// it instead of the key.
+    pub entity: Option<Vec<u8>>,
+}
+
+impl Record {
+    /// Record for the dead letter topic when this one cannot be sent. The payload is dropped, since it is most likely
+    /// too large to be sent anywhere, and only its size is kept.
+    pub fn into_dead_letter(self, dead_letter_topic: &str, error: &(dyn StdError + 'static)) -> Record {
+        let mut headers = self.headers;
+        headers.insert(HEADER_DLQ_TOPIC, self.topic);
+        headers.insert(HEADER_DLQ_ERROR, error_chain(error));
+        headers.insert(HEADER_DLQ_PAYLOAD_SIZE, self.payload.len().to_string());
+
+        Record {
+            topic: dead_letter_topic.to_string(),
+            payload: Vec::new(),
+            descriptor: None,
+            headers,
+            ..self
+        }
+    }
+}
+
+pub trait IntoRecord {
+    /// Serializes the message with the encoding configured for its topic, and reads its entity with the key strategy
+    /// configured for its protobuf type.
+    fn into_record(self, config: &RecordConfig) -> Result<Record, RecordError>;
+}
+
+impl IntoRecord for Record {
+    fn into_record(self, _: &RecordConfig) -> Result<Record, RecordError> {
+        Ok(self)
+    }
+}
+
+/// Serializes a batch. With a dead letter topic, messages which cannot be encoded are replaced by a dead letter
+/// record instead of failing the whole batch.
+pub fn into_records<M: IntoRecord>(messages: Vec<M>, config: &RecordConfig, dead_letter_topic: Option<&str>) -> Result<Vec<Record>, RecordError> {
+    messages.into_iter()
+        .map(|m| match (m.into_record(config), dead_letter_topic) {
+            (Err(err), Some(topic)) => {
+                warn!("sending message to the dead letter topic {}: {}", topic, error_chain(&err));
+                Ok(err.into_dead_letter(topic))
+            }
+            (result, _) => result,
+        })
+        .collect()
+}
+
+fn error_chain(error: &(dyn StdError + 'static)) -> String {
+    let mut chain = error.to_string();
+    let mut source = error.source();
+    while let Some(err) = source {
+        chain = format!("{}: {}", chain, err);
+        source = err.source();
+    }
+    chain
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::kafka_utils::proto_test::evm::Block;
+    use crate::kafka_utils::proto_test::utils;
+
+    use super::*;
+
+    #[test]
+    fn encode_as_protobuf_and_json() {
+        let eth_block = utils::build_block();
+
+        let bytes = Encoding::Protobuf.encode(&eth_block).unwrap();
+        assert_eq!(<Block as protobuf::Message>::parse_from_bytes(&bytes).unwrap(), eth_block);
+
+        let json = Encoding::Json.encode(&eth_block).unwrap();
+        let parsed: Block = protobuf_json_mapping::parse_from_str(std::str::from_utf8(&json).unwrap()).unwrap();
+        assert_eq!(parsed, eth_block);
+    }
+
+    #[test]
+    fn topic_encoding_overrides_default() {
+        let encoding = TopicEncoding {
+            default: Encoding::Protobuf,
+            topics: HashMap::from([("prod.fct.nakji.ethereum.0_1_0.evm_Block".to_string(), Encoding::Json)]),
+        };
+
+        assert_eq!(encoding.for_topic("prod.fct.nakji.ethereum.0_1_0.evm_Block"), Encoding::Json);
+        assert_eq!(encoding.for_topic("prod.fct.nakji.ethereum.0_1_0.evm_Transaction"), Encoding::Protobuf);
+        assert_eq!("json".parse::<Encoding>(), Ok(Encoding::Json));
+        assert!("avro".parse::<Encoding>().is_err());
+    }
+
+    const DEAD_LETTER_TOPIC: &str = "test.sys.nakji.ethereum.0_1_0.dlq";
+
+    fn build_record() -> Record {
+        utils::build_record("ethereum.Block", vec![8, 1, 16, 2])
+    }
+
+    enum TestMessage {
+        Valid,
+        Invalid,
+    }
+
+    impl IntoRecord for TestMessage {
+        fn into_record(self, _: &RecordConfig) -> Result<Record, RecordError> {
+            match self {
+                TestMessage::Valid => Ok(build_record()),
+                TestMessage::Invalid => Err(RecordError::new(
+                    utils::BLOCK_TOPIC.to_string(),
+                    b"ethereum.Block".to_vec(),
+                    "nakji.evm.Block".to_string(),
+                    Headers::new(),
+                    "number: 1".to_string(),
+                    EncodeError::Protobuf(std::io::Error::new(std::io::ErrorKind::InvalidData, "missing field").into()),
+                )),
+            }
+        }
+    }
+
+    #[test]
+    fn unencodable_messages_go_to_dead_letter_topic() {
+        let messages = || vec![TestMessage::Valid, TestMessage::Invalid, TestMessage::Valid];
+
+        assert!(into_records(messages(), &RecordConfig::default(), None).is_err());
+
+        let records = into_records(messages(), &RecordConfig::default(), Some(DEAD_LETTER_TOPIC)).unwrap();
+        assert_eq!(records.iter().map(|r| r.topic.as_str()).collect::<Vec<_>>(), vec![utils::BLOCK_TOPIC, DEAD_LETTER_TOPIC, utils::BLOCK_TOPIC]);
+
+        let dead_letter = &records[1];
+        assert_eq!(dead_letter.key, b"ethereum.Block");
+        assert_eq!(dead_letter.payload, b"number: 1");
+        assert_eq!(dead_letter.headers.get(HEADER_DLQ_TOPIC), Some(utils::BLOCK_TOPIC.as_bytes()));
+        assert_eq!(dead_letter.headers.get(HEADER_CONTENT_TYPE), Some("text/plain".as_bytes()));
+        let error = String::from_utf8(dead_letter.headers.get(HEADER_DLQ_ERROR).unwrap().to_vec()).unwrap();
+        assert!(error.contains("missing field"), "{}", error);
+    }
+
+    #[test]
+    fn oversized_record_to_dead_letter_topic() {
+        let error = std::io::Error::new(std::io::ErrorKind::InvalidInput, "message too large");
+
+        let dead_letter = build_record().into_dead_letter(DEAD_LETTER_TOPIC, &error);
+
+        assert_eq!(dead_letter.topic, DEAD_LETTER_TOPIC);
+        assert!(dead_letter.payload.is_empty());
+        assert_eq!(dead_letter.headers.get(HEADER_DLQ_TOPIC), Some(utils::BLOCK_TOPIC.as_bytes()));
+        assert_eq!(dead_letter.headers.get(HEADER_DLQ_ERROR), Some("message too large".as_bytes()));
+        assert_eq!(dead_letter.headers.get(HEADER_DLQ_PAYLOAD_SIZE), Some("4".as_bytes()));
+    }
+}
@@ -1,9 +1,10 @@
 use std::fmt;
 use std::str::FromStr;
 
-use protobuf::MessageDyn;
+use protobuf::{MessageDyn, MessageFull};
+use protobuf::reflect::MessageDescriptor;
 use semver::Version;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 pub const TOPIC_CONTEXT_SEPARATOR: &str = ".";
 pub const TOPIC_CONTRACT_SEPARATOR: &str = "_";
@@ -34,6 +35,12 @@ impl Topic {
         }
     }
 
+    /// Topic of the protobuf message `T`, with the event name derived from its descriptor.
+    pub fn for_message<T: MessageFull>(env: Env, message_type: MessageType, author: String, connector_name: String,
+                                       version: Version) -> Self {
+        Self::new(env, message_type, author, connector_name, version, get_event_name_from_descriptor(&T::descriptor()))
+    }
+
     pub fn to_schema(&self) -> String {
         let version = self.version.to_string().replace(TOPIC_CONTEXT_SEPARATOR, TOPIC_CONTRACT_SEPARATOR);
 
@@ -95,8 +102,8 @@ impl fmt::Display for Env {
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Serialize)]
-#[serde(rename_all(serialize = "lowercase"))]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum MessageType {
     FCT,
     BF,
@@ -117,8 +124,17 @@ impl MessageType {
     }
 }
 
+impl fmt::Display for MessageType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
 pub fn get_event_name(protobuf_message: Box<dyn MessageDyn>) -> String {
-    let message_descriptor = protobuf_message.descriptor_dyn();
+    get_event_name_from_descriptor(&protobuf_message.descriptor_dyn())
+}
+
+pub fn get_event_name_from_descriptor(message_descriptor: &MessageDescriptor) -> String {
     let full_name = message_descriptor.full_name().to_string();
     let name_slice: Vec<_> = full_name.split(TOPIC_CONTEXT_SEPARATOR).collect();
     let event_name = &name_slice[name_slice.len() - 2..].join(TOPIC_CONTRACT_SEPARATOR);
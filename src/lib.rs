@@ -2,4 +2,6 @@ pub mod connector;
 pub mod kafka_utils;
 mod config;
 mod manifest;
-pub mod proto_registry;
\ No newline at end of file
+pub mod proto_registry;
+pub mod shutdown;
+pub mod sink;
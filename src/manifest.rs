@@ -15,8 +15,10 @@ const MANIFEST_KEY_VERSION: &str = "version";
 
 impl Manifest {
     pub fn init() -> Self {
-        let yaml = Manifest::read_from_file();
+        Manifest::from_yaml(&Manifest::read_from_file())
+    }
 
+    pub fn from_yaml(yaml: &Value) -> Self {
         let name = yaml[MANIFEST_KEY_NAME]
             .as_str()
             .expect("name should be a string")
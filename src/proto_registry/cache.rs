@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+
+// <registry location>/<topic> -> descriptor hash of everything that was successfully registered from this machine,
+// relative to the working directory unless protoregistry.cache_path is set
+pub const DESCRIPTOR_CACHE_FILE: &str = ".nakji/protoregistry.cache.json";
+
+/// Returns the hex encoded sha256 of a descriptor set together with the name of the message it describes,
+/// so the same descriptor registered for two different messages does not collide.
+pub fn descriptor_hash(proto_message_name: &str, descriptor: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(proto_message_name.as_bytes());
+    hasher.update([0]);
+    hasher.update(descriptor);
+    hex::encode(hasher.finalize())
+}
+
+pub struct DescriptorCache {
+    path: PathBuf,
+    hashes: HashMap<String, String>,
+}
+
+impl DescriptorCache {
+    pub async fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+
+        let hashes = match fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|err| {
+                warn!("ignoring corrupted descriptor cache {:?}: {}", path, err);
+                HashMap::new()
+            }),
+            Err(err) if err.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                warn!("failed to read descriptor cache {:?}: {}", path, err);
+                HashMap::new()
+            }
+        };
+
+        DescriptorCache { path, hashes }
+    }
+
+    pub fn contains(&self, key: &str, hash: &str) -> bool {
+        self.hashes.get(key).map(|h| h == hash).unwrap_or(false)
+    }
+
+    pub fn insert(&mut self, key: String, hash: String) {
+        self.hashes.insert(key, hash);
+    }
+
+    pub async fn save(&self) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let bytes = serde_json::to_vec_pretty(&self.hashes).expect("failed to serialize descriptor cache");
+        fs::write(&self.path, bytes).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    #[test]
+    fn hash_is_stable_and_depends_on_message_name() {
+        let descriptor = vec![10, 9, 101, 118, 109];
+
+        let hash = descriptor_hash("nakji.evm.Block", &descriptor);
+
+        assert_eq!(hash, descriptor_hash("nakji.evm.Block", &descriptor));
+        assert_eq!(hash.len(), 64);
+        assert_ne!(hash, descriptor_hash("nakji.evm.Transaction", &descriptor));
+        assert_ne!(hash, descriptor_hash("nakji.evm.Block", &descriptor[1..]));
+    }
+
+    #[tokio::test]
+    async fn cache_survives_reload() {
+        let path = env::temp_dir().join("nakji-connector-test").join("protoregistry.cache.json");
+        let _ = fs::remove_file(&path);
+
+        let mut cache = DescriptorCache::load(&path).await;
+        assert!(!cache.contains("nakji.ethereum.0_0_0.evm_Block", "abc"));
+
+        cache.insert("nakji.ethereum.0_0_0.evm_Block".to_string(), "abc".to_string());
+        cache.save().await.expect("failed to save cache");
+
+        let cache = DescriptorCache::load(&path).await;
+        assert!(cache.contains("nakji.ethereum.0_0_0.evm_Block", "abc"));
+        assert!(!cache.contains("nakji.ethereum.0_0_0.evm_Block", "def"));
+
+        fs::remove_file(&path).expect("failed to remove cache");
+    }
+}
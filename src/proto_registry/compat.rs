@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use protobuf::descriptor::{DescriptorProto, FieldDescriptorProto, FileDescriptorSet};
+use protobuf::descriptor::field_descriptor_proto::Label;
+use protobuf::Message;
+
+use crate::kafka_utils::TOPIC_CONTEXT_SEPARATOR;
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum CompatibilityMode {
+    // no checks, any schema change is accepted
+    None,
+    // consumers using the local schema can read data produced with the registered one
+    #[default]
+    Backward,
+    // consumers using the registered schema can read data produced with the local one
+    Forward,
+    // both backward and forward
+    Full,
+}
+
+impl CompatibilityMode {
+    fn as_str(&self) -> &str {
+        match self {
+            CompatibilityMode::None => "none",
+            CompatibilityMode::Backward => "backward",
+            CompatibilityMode::Forward => "forward",
+            CompatibilityMode::Full => "full",
+        }
+    }
+}
+
+impl FromStr for CompatibilityMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(CompatibilityMode::None),
+            "backward" => Ok(CompatibilityMode::Backward),
+            "forward" => Ok(CompatibilityMode::Forward),
+            "full" => Ok(CompatibilityMode::Full),
+            _ => Err(format!("'{}' is not a valid value for compat::CompatibilityMode", s)),
+        }
+    }
+}
+
+impl fmt::Display for CompatibilityMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Incompatibility {
+    MessageNotFound(String),
+    FieldNumberReused { number: i32, registered: String, local: String },
+    FieldRenumbered { field: String, registered: i32, local: i32 },
+    FieldTypeChanged { field: String, registered: String, local: String },
+    RequiredFieldRemoved(String),
+    RequiredFieldAdded(String),
+}
+
+impl Incompatibility {
+    fn applies_to(&self, mode: CompatibilityMode) -> bool {
+        match self {
+            Incompatibility::RequiredFieldRemoved(_) => matches!(mode, CompatibilityMode::Forward | CompatibilityMode::Full),
+            Incompatibility::RequiredFieldAdded(_) => matches!(mode, CompatibilityMode::Backward | CompatibilityMode::Full),
+            _ => mode != CompatibilityMode::None,
+        }
+    }
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Incompatibility::MessageNotFound(name) => write!(f, "message {name} not found in descriptor"),
+            Incompatibility::FieldNumberReused { number, registered, local } => write!(f, "field number {number} is reused: {registered} -> {local}"),
+            Incompatibility::FieldRenumbered { field, registered, local } => write!(f, "field {field} is renumbered: {registered} -> {local}"),
+            Incompatibility::FieldTypeChanged { field, registered, local } => write!(f, "field {field} changed type: {registered} -> {local}"),
+            Incompatibility::RequiredFieldRemoved(field) => write!(f, "required field {field} is removed"),
+            Incompatibility::RequiredFieldAdded(field) => write!(f, "required field {field} is added"),
+        }
+    }
+}
+
+/// Compares the local descriptor set of `message_name` against the registered one and returns every change
+/// which breaks the given compatibility mode.
+pub fn check(registered: &[u8], local: &[u8], message_name: &str, mode: CompatibilityMode) -> Result<Vec<Incompatibility>, protobuf::Error> {
+    if mode == CompatibilityMode::None {
+        return Ok(vec![]);
+    }
+
+    let registered_set = FileDescriptorSet::parse_from_bytes(registered)?;
+    let local_set = FileDescriptorSet::parse_from_bytes(local)?;
+
+    let registered_message = match find_message(&registered_set, message_name) {
+        Some(m) => m,
+        // nothing to be compatible with, e.g. the topic was registered with a different message
+        None => return Ok(vec![]),
+    };
+    let local_message = match find_message(&local_set, message_name) {
+        Some(m) => m,
+        None => return Ok(vec![Incompatibility::MessageNotFound(message_name.to_string())]),
+    };
+
+    let incompatibilities = compare_fields(registered_message, local_message)
+        .into_iter()
+        .filter(|i| i.applies_to(mode))
+        .collect();
+
+    Ok(incompatibilities)
+}
+
+fn compare_fields(registered: &DescriptorProto, local: &DescriptorProto) -> Vec<Incompatibility> {
+    let mut incompatibilities = Vec::new();
+
+    let local_by_number: HashMap<i32, &FieldDescriptorProto> = local.field.iter().map(|f| (f.number(), f)).collect();
+    let local_by_name: HashMap<&str, &FieldDescriptorProto> = local.field.iter().map(|f| (f.name(), f)).collect();
+    let registered_by_number: HashMap<i32, &FieldDescriptorProto> = registered.field.iter().map(|f| (f.number(), f)).collect();
+
+    for registered_field in &registered.field {
+        match local_by_number.get(&registered_field.number()) {
+            Some(local_field) if local_field.name() != registered_field.name() => {
+                incompatibilities.push(Incompatibility::FieldNumberReused {
+                    number: registered_field.number(),
+                    registered: registered_field.name().to_string(),
+                    local: local_field.name().to_string(),
+                });
+            }
+            Some(local_field) => {
+                let registered_type = field_type(registered_field);
+                let local_type = field_type(local_field);
+                if registered_type != local_type {
+                    incompatibilities.push(Incompatibility::FieldTypeChanged {
+                        field: registered_field.name().to_string(),
+                        registered: registered_type,
+                        local: local_type,
+                    });
+                }
+            }
+            None => {
+                if let Some(local_field) = local_by_name.get(registered_field.name()) {
+                    incompatibilities.push(Incompatibility::FieldRenumbered {
+                        field: registered_field.name().to_string(),
+                        registered: registered_field.number(),
+                        local: local_field.number(),
+                    });
+                } else if registered_field.label() == Label::LABEL_REQUIRED {
+                    incompatibilities.push(Incompatibility::RequiredFieldRemoved(registered_field.name().to_string()));
+                }
+            }
+        }
+    }
+
+    for local_field in &local.field {
+        if !registered_by_number.contains_key(&local_field.number()) && local_field.label() == Label::LABEL_REQUIRED {
+            incompatibilities.push(Incompatibility::RequiredFieldAdded(local_field.name().to_string()));
+        }
+    }
+
+    incompatibilities
+}
+
+fn field_type(field: &FieldDescriptorProto) -> String {
+    let label = match field.label() {
+        Label::LABEL_OPTIONAL => "optional",
+        Label::LABEL_REQUIRED => "required",
+        Label::LABEL_REPEATED => "repeated",
+    };
+
+    if field.type_name().is_empty() {
+        format!("{} {:?}", label, field.type_())
+    } else {
+        format!("{} {}", label, field.type_name().trim_start_matches(TOPIC_CONTEXT_SEPARATOR))
+    }
+}
+
+fn find_message<'a>(set: &'a FileDescriptorSet, full_name: &str) -> Option<&'a DescriptorProto> {
+    set.file.iter().find_map(|file| {
+        let prefix = if file.package().is_empty() {
+            String::new()
+        } else {
+            file.package().to_string() + TOPIC_CONTEXT_SEPARATOR
+        };
+        find_nested_message(&file.message_type, &prefix, full_name)
+    })
+}
+
+fn find_nested_message<'a>(messages: &'a [DescriptorProto], prefix: &str, full_name: &str) -> Option<&'a DescriptorProto> {
+    messages.iter().find_map(|message| {
+        let name = prefix.to_string() + message.name();
+        if name == full_name {
+            return Some(message);
+        }
+        find_nested_message(&message.nested_type, &(name + TOPIC_CONTEXT_SEPARATOR), full_name)
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use protobuf::descriptor::field_descriptor_proto::Type;
+
+    use crate::kafka_utils::proto_test::evm;
+
+    use super::*;
+
+    fn evm_descriptor_set() -> FileDescriptorSet {
+        let file = evm::file_descriptor();
+        let mut set = FileDescriptorSet::new();
+        set.file = file.deps().iter().map(|d| d.proto().clone()).collect();
+        set.file.push(file.proto().clone());
+        set
+    }
+
+    fn block_fields(set: &mut FileDescriptorSet) -> &mut Vec<FieldDescriptorProto> {
+        let file = set.file.last_mut().unwrap();
+        let block = file.message_type.iter_mut().find(|m| m.name() == "Block").unwrap();
+        &mut block.field
+    }
+
+    #[test]
+    fn same_schema_is_compatible() {
+        let bytes = evm_descriptor_set().write_to_bytes().unwrap();
+
+        let incompatibilities = check(&bytes, &bytes, "nakji.evm.Block", CompatibilityMode::Full).unwrap();
+
+        assert!(incompatibilities.is_empty());
+    }
+
+    #[test]
+    fn added_optional_field_is_compatible() {
+        let registered = evm_descriptor_set();
+        let mut local = registered.clone();
+        let mut field = FieldDescriptorProto::new();
+        field.set_name("miner".to_string());
+        field.set_number(8);
+        field.set_type(Type::TYPE_STRING);
+        field.set_label(Label::LABEL_OPTIONAL);
+        block_fields(&mut local).push(field);
+
+        let incompatibilities = check(&registered.write_to_bytes().unwrap(), &local.write_to_bytes().unwrap(), "nakji.evm.Block", CompatibilityMode::Full).unwrap();
+
+        assert!(incompatibilities.is_empty());
+    }
+
+    #[test]
+    fn changed_field_type_is_incompatible() {
+        let registered = evm_descriptor_set();
+        let mut local = registered.clone();
+        let number = block_fields(&mut local).iter_mut().find(|f| f.name() == "number").unwrap();
+        number.set_type(Type::TYPE_STRING);
+
+        let incompatibilities = check(&registered.write_to_bytes().unwrap(), &local.write_to_bytes().unwrap(), "nakji.evm.Block", CompatibilityMode::Backward).unwrap();
+
+        assert_eq!(incompatibilities, vec![Incompatibility::FieldTypeChanged {
+            field: "number".to_string(),
+            registered: "optional TYPE_UINT64".to_string(),
+            local: "optional TYPE_STRING".to_string(),
+        }]);
+    }
+
+    #[test]
+    fn renumbered_fields_are_incompatible() {
+        let registered = evm_descriptor_set();
+        let mut local = registered.clone();
+        let fields = block_fields(&mut local);
+        fields.iter_mut().find(|f| f.name() == "nonce").unwrap().set_number(8);
+        fields.iter_mut().find(|f| f.name() == "gas_used").unwrap().set_number(7);
+
+        let incompatibilities = check(&registered.write_to_bytes().unwrap(), &local.write_to_bytes().unwrap(), "nakji.evm.Block", CompatibilityMode::Forward).unwrap();
+
+        assert_eq!(incompatibilities, vec![
+            Incompatibility::FieldRenumbered { field: "gas_used".to_string(), registered: 6, local: 7 },
+            Incompatibility::FieldNumberReused { number: 7, registered: "nonce".to_string(), local: "gas_used".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn required_fields_depend_on_mode() {
+        let mut registered = evm_descriptor_set();
+        block_fields(&mut registered).iter_mut().find(|f| f.name() == "hash").unwrap().set_label(Label::LABEL_REQUIRED);
+        let mut local = registered.clone();
+        block_fields(&mut local).retain(|f| f.name() != "hash");
+
+        let registered = registered.write_to_bytes().unwrap();
+        let local = local.write_to_bytes().unwrap();
+
+        assert!(check(&registered, &local, "nakji.evm.Block", CompatibilityMode::Backward).unwrap().is_empty());
+        assert_eq!(check(&registered, &local, "nakji.evm.Block", CompatibilityMode::Forward).unwrap(), vec![Incompatibility::RequiredFieldRemoved("hash".to_string())]);
+        assert_eq!(check(&local, &registered, "nakji.evm.Block", CompatibilityMode::Full).unwrap(), vec![Incompatibility::RequiredFieldAdded("hash".to_string())]);
+        assert!(check(&local, &registered, "nakji.evm.Block", CompatibilityMode::None).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,79 @@
+use async_trait::async_trait;
+use log::info;
+use reqwest::StatusCode;
+
+use crate::kafka_utils::MessageType;
+
+use super::{ProtoRegistry, ProtoRegistryError, TopicProtoMsg};
+
+const REGISTER_PATH: &str = "/v1/register";
+// GET /v1/descriptor/<msg_type>/<topic>, answering 404 for a topic that is not registered
+const DESCRIPTOR_PATH: &str = "/v1/descriptor";
+
+/// Registry backed by the protoregistry service.
+pub struct HttpProtoRegistry {
+    host: String,
+    client: reqwest::Client,
+    descriptor_lookup: bool,
+}
+
+impl HttpProtoRegistry {
+    /// `host` is the base URL of the service, e.g. `http://localhost:9191`. A host without a scheme is reached over
+    /// plain http.
+    pub fn new(host: &str) -> Self {
+        let host = host.trim_end_matches('/');
+        let host = if host.contains("://") { host.to_string() } else { format!("http://{}", host) };
+
+        HttpProtoRegistry {
+            host,
+            client: reqwest::Client::new(),
+            descriptor_lookup: false,
+        }
+    }
+
+    /// Reads registered descriptors from `GET /v1/descriptor`, selected with `protoregistry.descriptor_lookup` in
+    /// config.yaml. Only enable it for a service serving this route: any 404 is read as a topic which is not
+    /// registered, so a service without it would pass every compatibility check. Without it `get` fails with
+    /// `ProtoRegistryError::LookupUnsupported`.
+    pub fn with_descriptor_lookup(mut self, descriptor_lookup: bool) -> Self {
+        self.descriptor_lookup = descriptor_lookup;
+        self
+    }
+}
+
+#[async_trait]
+impl ProtoRegistry for HttpProtoRegistry {
+    fn location(&self) -> String {
+        self.host.clone()
+    }
+
+    async fn register(&self, topic_proto_messages: &[TopicProtoMsg]) -> Result<(), ProtoRegistryError> {
+        let bytes = serde_json::to_vec(topic_proto_messages).expect("failed to serialize topic_proto_messages to bytes");
+
+        let res = self.client.post(format!("{}{}", self.host, REGISTER_PATH))
+            .body(bytes)
+            .send()
+            .await?
+            .error_for_status()?;
+        info!("response: {:#?}", res);
+
+        Ok(())
+    }
+
+    async fn get(&self, message_type: &MessageType, topic: &str) -> Result<Option<TopicProtoMsg>, ProtoRegistryError> {
+        if !self.descriptor_lookup {
+            return Err(ProtoRegistryError::LookupUnsupported(self.location()));
+        }
+
+        let res = self.client.get(format!("{}{}/{}/{}", self.host, DESCRIPTOR_PATH, message_type, topic))
+            .send()
+            .await?;
+
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let tpm = res.error_for_status()?.json::<TopicProtoMsg>().await?;
+        Ok(Some(tpm))
+    }
+}
@@ -0,0 +1,104 @@
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use log::debug;
+use tokio::fs;
+
+use crate::kafka_utils::{MessageType, TOPIC_CONTEXT_SEPARATOR};
+
+use super::{ProtoRegistry, ProtoRegistryError, TopicProtoMsg};
+
+pub const LOCAL_REGISTRY_DIR: &str = ".nakji/registry";
+
+/// Registry writing every topic to `<dir>/<msg_type>.<topic>.json`, used in dev mode where protoregistry is not available.
+/// The raw descriptor set is also written to `<dir>/<msg_type>.<topic>.desc`, so messages can be decoded with
+/// `protoc --decode=<proto_msg> --descriptor_set_in=<dir>/<msg_type>.<topic>.desc`.
+pub struct LocalProtoRegistry {
+    dir: PathBuf,
+}
+
+impl LocalProtoRegistry {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        LocalProtoRegistry { dir: dir.into() }
+    }
+
+    fn path(&self, message_type: &MessageType, topic: &str, extension: &str) -> PathBuf {
+        self.dir.join(format!("{}{}{}.{}", message_type, TOPIC_CONTEXT_SEPARATOR, topic, extension))
+    }
+}
+
+#[async_trait]
+impl ProtoRegistry for LocalProtoRegistry {
+    fn location(&self) -> String {
+        self.dir.to_string_lossy().to_string()
+    }
+
+    async fn register(&self, topic_proto_messages: &[TopicProtoMsg]) -> Result<(), ProtoRegistryError> {
+        fs::create_dir_all(&self.dir).await?;
+
+        for tpm in topic_proto_messages {
+            let bytes = serde_json::to_vec_pretty(tpm).expect("failed to serialize topic_proto_message to bytes");
+            fs::write(self.path(&tpm.message_type, &tpm.topic_name, "json"), bytes).await?;
+            fs::write(self.path(&tpm.message_type, &tpm.topic_name, "desc"), &tpm.descriptor).await?;
+            debug!("registered topic {} in {:?}", tpm.topic_name, self.dir);
+        }
+
+        Ok(())
+    }
+
+    async fn get(&self, message_type: &MessageType, topic: &str) -> Result<Option<TopicProtoMsg>, ProtoRegistryError> {
+        match fs::read(self.path(message_type, topic, "json")).await {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use crate::kafka_utils::MessageType;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn register_and_get_topic() {
+        let dir = env::temp_dir().join("nakji-connector-test").join("registry");
+        let _ = fs::remove_dir_all(&dir);
+        let registry = LocalProtoRegistry::new(&dir);
+
+        let tpm = TopicProtoMsg {
+            message_type: MessageType::FCT,
+            topic_name: "nakji.ethereum.0_0_0.evm_Block".to_string(),
+            proto_message_name: "nakji.evm.Block".to_string(),
+            descriptor: vec![10, 9, 101, 118, 109],
+        };
+
+        assert_eq!(registry.get(&MessageType::FCT, &tpm.topic_name).await.unwrap(), None);
+
+        registry.register(std::slice::from_ref(&tpm)).await.unwrap();
+
+        assert_eq!(registry.get(&MessageType::FCT, &tpm.topic_name).await.unwrap(), Some(tpm.clone()));
+        assert_eq!(registry.get(&MessageType::BF, &tpm.topic_name).await.unwrap(), None);
+        assert_eq!(fs::read(dir.join("fct.nakji.ethereum.0_0_0.evm_Block.desc")).unwrap(), tpm.descriptor);
+
+        fs::remove_dir_all(&dir).expect("failed to remove registry dir");
+    }
+
+    #[tokio::test]
+    async fn corrupted_topic_is_a_serde_error() {
+        let dir = env::temp_dir().join("nakji-connector-test").join("corrupted-registry");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("fct.nakji.ethereum.0_0_0.evm_Block.json"), b"{").unwrap();
+        let registry = LocalProtoRegistry::new(&dir);
+
+        let err = registry.get(&MessageType::FCT, "nakji.ethereum.0_0_0.evm_Block").await.unwrap_err();
+        assert!(matches!(err, ProtoRegistryError::Serde(_)));
+
+        fs::remove_dir_all(&dir).expect("failed to remove registry dir");
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use log::{debug, warn};
+
+use crate::kafka_utils::MessageType;
+
+use super::TopicProtoMsg;
+
+#[derive(Default)]
+struct State {
+    topics: HashMap<(MessageType, String), TopicProtoMsg>,
+    requests: Vec<Vec<TopicProtoMsg>>,
+    fail_with: Option<u16>,
+}
+
+/// In-process protoregistry serving `POST /v1/register` and `GET /v1/descriptor/<msg_type>/<topic>` on a random local port.
+/// Every registration is recorded so tests can assert on what a connector sent.
+///
+/// The server is stopped when the mock is dropped.
+pub struct MockProtoRegistry {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl MockProtoRegistry {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind mock protoregistry");
+        let addr = listener.local_addr().expect("failed to get mock protoregistry address");
+        let state = Arc::new(Mutex::new(State::default()));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let thread_state = state.clone();
+        let thread_stopped = stopped.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thread_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        if let Err(err) = handle(stream, &thread_state) {
+                            warn!("mock protoregistry failed to handle request: {}", err);
+                        }
+                    }
+                    Err(err) => warn!("mock protoregistry failed to accept connection: {}", err),
+                }
+            }
+        });
+
+        MockProtoRegistry { addr, state, stopped }
+    }
+
+    /// Base URL to put in `protoregistry.host` or pass to `HttpProtoRegistry::new`.
+    pub fn host(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Every topic received, in the order the registrations arrived.
+    pub fn received(&self) -> Vec<TopicProtoMsg> {
+        self.state.lock().unwrap().requests.iter().flatten().cloned().collect()
+    }
+
+    /// Payloads of the register requests, one entry per request.
+    pub fn requests(&self) -> Vec<Vec<TopicProtoMsg>> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Marks a topic as already registered.
+    pub fn insert(&self, topic_proto_message: TopicProtoMsg) {
+        let key = (topic_proto_message.message_type.clone(), topic_proto_message.topic_name.clone());
+        self.state.lock().unwrap().topics.insert(key, topic_proto_message);
+    }
+
+    /// Answers every following request with the given status code, until `succeed` is called.
+    pub fn fail_with(&self, status: u16) {
+        self.state.lock().unwrap().fail_with = Some(status);
+    }
+
+    pub fn succeed(&self) {
+        self.state.lock().unwrap().fail_with = None;
+    }
+}
+
+impl Drop for MockProtoRegistry {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wake up the accept loop so it sees the flag
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+fn handle(stream: TcpStream, state: &Mutex<State>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    debug!("mock protoregistry received {} {}", method, path);
+
+    let (status, response) = respond(&method, &path, &body, &mut state.lock().unwrap());
+    write_response(stream, status, &response)
+}
+
+fn respond(method: &str, path: &str, body: &[u8], state: &mut State) -> (u16, Vec<u8>) {
+    if let Some(status) = state.fail_with {
+        return (status, b"mock failure".to_vec());
+    }
+
+    match (method, path) {
+        ("POST", "/v1/register") => match serde_json::from_slice::<Vec<TopicProtoMsg>>(body) {
+            Ok(topic_proto_messages) => {
+                for tpm in &topic_proto_messages {
+                    state.topics.insert((tpm.message_type.clone(), tpm.topic_name.clone()), tpm.clone());
+                }
+                state.requests.push(topic_proto_messages);
+                (200, b"{}".to_vec())
+            }
+            Err(err) => (400, err.to_string().into_bytes()),
+        },
+        ("GET", path) if path.starts_with("/v1/descriptor/") => {
+            let registered = path.trim_start_matches("/v1/descriptor/")
+                .split_once('/')
+                .and_then(|(message_type, topic)| {
+                    let message_type = serde_json::from_value(serde_json::Value::from(message_type)).ok()?;
+                    state.topics.get(&(message_type, topic.to_string()))
+                });
+            match registered {
+                Some(tpm) => (200, serde_json::to_vec(tpm).expect("failed to serialize topic_proto_message")),
+                None => (404, b"topic not found".to_vec()),
+            }
+        }
+        _ => (404, b"not found".to_vec()),
+    }
+}
+
+fn write_response(mut stream: TcpStream, status: u16, body: &[u8]) -> std::io::Result<()> {
+    let header = format!(
+        "HTTP/1.1 {} MOCK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len(),
+    );
+    stream.write_all(header.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::proto_registry::{HttpProtoRegistry, ProtoRegistry, ProtoRegistryError};
+
+    use super::*;
+
+    fn build_topic_proto_message(topic_name: &str) -> TopicProtoMsg {
+        TopicProtoMsg {
+            message_type: MessageType::FCT,
+            topic_name: topic_name.to_string(),
+            proto_message_name: "nakji.evm.Block".to_string(),
+            descriptor: vec![10, 9, 101, 118, 109],
+        }
+    }
+
+    #[tokio::test]
+    async fn records_registrations() {
+        let mock = MockProtoRegistry::start();
+        let registry = HttpProtoRegistry::new(&mock.host()).with_descriptor_lookup(true);
+
+        let block = build_topic_proto_message("nakji.ethereum.0_0_0.evm_Block");
+        let transaction = build_topic_proto_message("nakji.ethereum.0_0_0.evm_Transaction");
+        registry.register(&[block.clone(), transaction.clone()]).await.unwrap();
+
+        assert_eq!(mock.requests(), vec![vec![block.clone(), transaction.clone()]]);
+        assert_eq!(mock.received(), vec![block.clone(), transaction]);
+        assert_eq!(registry.get(&MessageType::FCT, &block.topic_name).await.unwrap(), Some(block));
+    }
+
+    #[tokio::test]
+    async fn serves_inserted_topics() {
+        let mock = MockProtoRegistry::start();
+        let registry = HttpProtoRegistry::new(&mock.host()).with_descriptor_lookup(true);
+
+        let block = build_topic_proto_message("nakji.ethereum.0_0_0.evm_Block");
+        mock.insert(block.clone());
+
+        assert_eq!(registry.get(&MessageType::FCT, &block.topic_name).await.unwrap(), Some(block.clone()));
+        assert_eq!(registry.get(&MessageType::BF, &block.topic_name).await.unwrap(), None);
+        assert_eq!(registry.get(&MessageType::FCT, "nakji.ethereum.0_0_0.evm_Transaction").await.unwrap(), None);
+        assert!(mock.received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn descriptor_lookup_is_opt_in() {
+        let mock = MockProtoRegistry::start();
+        let registry = HttpProtoRegistry::new(&mock.host());
+        mock.fail_with(500);
+
+        let err = registry.get(&MessageType::FCT, "nakji.ethereum.0_0_0.evm_Block").await.unwrap_err();
+        assert!(matches!(err, ProtoRegistryError::LookupUnsupported(location) if location == mock.host()));
+    }
+
+    #[tokio::test]
+    async fn accepts_host_without_scheme() {
+        let mock = MockProtoRegistry::start();
+        let host = mock.host().trim_start_matches("http://").to_string() + "/";
+        let registry = HttpProtoRegistry::new(&host);
+
+        assert_eq!(registry.location(), mock.host());
+        registry.register(&[build_topic_proto_message("nakji.ethereum.0_0_0.evm_Block")]).await.unwrap();
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fails_on_demand() {
+        let mock = MockProtoRegistry::start();
+        let registry = HttpProtoRegistry::new(&mock.host()).with_descriptor_lookup(true);
+        let block = build_topic_proto_message("nakji.ethereum.0_0_0.evm_Block");
+
+        mock.fail_with(500);
+        let err = registry.register(std::slice::from_ref(&block)).await.unwrap_err();
+        assert!(matches!(err, ProtoRegistryError::Request(e) if e.status().map(|s| s.as_u16()) == Some(500)));
+        assert!(mock.received().is_empty());
+
+        mock.succeed();
+        registry.register(std::slice::from_ref(&block)).await.unwrap();
+        assert_eq!(mock.received(), vec![block]);
+    }
+}
@@ -0,0 +1,254 @@
+pub mod cache;
+pub mod compat;
+pub mod http;
+pub mod local;
+#[cfg(any(test, feature = "testing"))]
+pub mod mock;
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use async_trait::async_trait;
+use log::{info, warn};
+use protobuf::{Message, MessageDyn};
+use protobuf::descriptor::FileDescriptorSet;
+use protobuf::reflect::FileDescriptor;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::kafka_utils::{MessageType, TOPIC_CONTEXT_SEPARATOR};
+
+pub use cache::{DESCRIPTOR_CACHE_FILE, DescriptorCache, descriptor_hash};
+pub use compat::{CompatibilityMode, Incompatibility};
+pub use http::HttpProtoRegistry;
+pub use local::{LOCAL_REGISTRY_DIR, LocalProtoRegistry};
+#[cfg(any(test, feature = "testing"))]
+pub use mock::MockProtoRegistry;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct TopicProtoMsg {
+    #[serde(rename = "msg_type")]
+    pub message_type: MessageType,
+    #[serde(rename = "topic")]
+    pub topic_name: String,
+    #[serde(rename = "proto_msg")]
+    pub proto_message_name: String,
+    pub descriptor: Vec<u8>,
+}
+
+/// Storage for topic -> descriptor mappings.
+#[async_trait]
+pub trait ProtoRegistry: Send + Sync {
+    /// Identifies the backend, e.g. the protoregistry host, so cached registrations are not shared between registries.
+    fn location(&self) -> String;
+
+    async fn register(&self, topic_proto_messages: &[TopicProtoMsg]) -> Result<(), ProtoRegistryError>;
+
+    async fn get(&self, message_type: &MessageType, topic: &str) -> Result<Option<TopicProtoMsg>, ProtoRegistryError>;
+}
+
+#[derive(Error, Debug)]
+pub enum ProtoRegistryError {
+    #[error("request to protoregistry failed")]
+    Request(#[from] reqwest::Error),
+    #[error("failed to parse protobuf descriptor")]
+    Descriptor(#[from] protobuf::Error),
+    /// Only the topic of the same manifest version is compared. A new manifest version registers new topics, which
+    /// consumers have to move to, so it is not checked against the previous version.
+    #[error("schema of topic {topic} is not {mode} compatible with the one registered for this manifest version: {}", .incompatibilities.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", "))]
+    Incompatible {
+        topic: String,
+        mode: CompatibilityMode,
+        incompatibilities: Vec<Incompatibility>,
+    },
+    #[error("failed to decode registered topic")]
+    Serde(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// The registry cannot return registered descriptors, e.g. the protoregistry service without descriptor lookup.
+    #[error("registry {0} cannot return registered descriptors")]
+    LookupUnsupported(String),
+}
+
+/// Outcome of a registration, listing `<msg_type>.<topic>` by whether their descriptor was already registered.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct RegistrationReport {
+    pub new: Vec<String>,
+    pub unchanged: Vec<String>,
+    pub changed: Vec<String>,
+    /// Registered without comparing them to the registered descriptor, since the registry cannot return it.
+    pub unchecked: Vec<String>,
+    /// Descriptor hash of every registered message, keyed by protobuf full name.
+    pub schema_hashes: HashMap<String, String>,
+}
+
+
+/// Registers every (message type, topic) pair in a single request to the registry, in the given order.
+/// Registrations are cached in `cache_path`, see `DescriptorCache`.
+pub async fn register_dynamic_topics(registry: &dyn ProtoRegistry, topic_types: Vec<((MessageType, String), Box<dyn MessageDyn>)>, mode: CompatibilityMode, cache_path: &Path) -> Result<RegistrationReport, ProtoRegistryError> {
+    let topic_proto_messages = build_topic_proto_messages(topic_types);
+
+    let location = registry.location();
+    let mut cache = DescriptorCache::load(cache_path).await;
+    let mut report = RegistrationReport::default();
+    let mut to_register: Vec<TopicProtoMsg> = Vec::new();
+
+    for tpm in topic_proto_messages {
+        let hash = descriptor_hash(&tpm.proto_message_name, &tpm.descriptor);
+        report.schema_hashes.insert(tpm.proto_message_name.clone(), hash.clone());
+        let name = format!("{}{}{}", tpm.message_type, TOPIC_CONTEXT_SEPARATOR, tpm.topic_name);
+        let cache_key = format!("{}/{}", location, name);
+        if cache.contains(&cache_key, &hash) {
+            report.unchanged.push(name);
+            continue;
+        }
+
+        let registered = match registry.get(&tpm.message_type, &tpm.topic_name).await {
+            Err(ProtoRegistryError::LookupUnsupported(location)) => {
+                if mode != CompatibilityMode::None {
+                    warn!("schema of {} is registered without a {} compatibility check, {} cannot return registered descriptors", name, mode, location);
+                }
+                cache.insert(cache_key, hash);
+                report.unchecked.push(name);
+                to_register.push(tpm);
+                continue;
+            }
+            registered => registered?,
+        };
+        match registered {
+            None => report.new.push(name),
+            Some(registered) if descriptor_hash(&registered.proto_message_name, &registered.descriptor) == hash => {
+                cache.insert(cache_key, hash);
+                report.unchanged.push(name);
+                continue;
+            }
+            Some(registered) => {
+                check_compatibility(&registered, &tpm, mode)?;
+                report.changed.push(name);
+            }
+        }
+
+        cache.insert(cache_key, hash);
+        to_register.push(tpm);
+    }
+
+    if !to_register.is_empty() {
+        registry.register(&to_register).await?;
+    }
+
+    cache.save().await?;
+    info!("registered topics, new: {:?}, changed: {:?}, unchecked: {:?}, unchanged: {:?}", report.new, report.changed, report.unchecked, report.unchanged);
+
+    Ok(report)
+}
+
+fn check_compatibility(registered: &TopicProtoMsg, tpm: &TopicProtoMsg, mode: CompatibilityMode) -> Result<(), ProtoRegistryError> {
+    let incompatibilities = compat::check(&registered.descriptor, &tpm.descriptor, &tpm.proto_message_name, mode)?;
+    if !incompatibilities.is_empty() {
+        return Err(ProtoRegistryError::Incompatible {
+            topic: tpm.topic_name.clone(),
+            mode,
+            incompatibilities,
+        });
+    }
+
+    Ok(())
+}
+
+fn build_topic_proto_messages(topic_types: Vec<((MessageType, String), Box<dyn MessageDyn>)>) -> Vec<TopicProtoMsg> {
+    let mut topic_proto_messages: Vec<TopicProtoMsg> = Vec::new();
+
+    for ((message_type, topic), message) in topic_types {
+        let message_descriptor = message.descriptor_dyn();
+
+        let topic_proto_message = TopicProtoMsg {
+            message_type,
+            topic_name: topic,
+            proto_message_name: message_descriptor.full_name().to_string(),
+            descriptor: descriptor_set(message_descriptor.file_descriptor()),
+        };
+        topic_proto_messages.push(topic_proto_message);
+    }
+
+    topic_proto_messages
+}
+
+/// Serialized `FileDescriptorSet` of the file and every file it imports, dependencies first. It is the same as the
+/// output of `protoc --include_imports --descriptor_set_out`, built from the descriptors compiled into the binary.
+pub fn descriptor_set(file: &FileDescriptor) -> Vec<u8> {
+    let mut set = FileDescriptorSet::new();
+    add_file(file, &mut set, &mut HashSet::new());
+    set.write_to_bytes().expect("failed to serialize descriptor set")
+}
+
+fn add_file(file: &FileDescriptor, set: &mut FileDescriptorSet, added: &mut HashSet<String>) {
+    if !added.insert(file.name().to_string()) {
+        return;
+    }
+    for dependency in file.deps() {
+        add_file(dependency, set, added);
+    }
+
+    // protoc only keeps comments with --include_source_info
+    let mut proto = file.proto().clone();
+    proto.source_code_info.clear();
+    set.file.push(proto);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::kafka_utils::proto_test::utils;
+
+    use super::*;
+
+    #[test]
+    fn test_build_topic_proto_messages() {
+        let eth_block = utils::build_block();
+        let eth_transaction = utils::build_transaction();
+
+        let topic_types: Vec<((MessageType, String), Box<dyn MessageDyn>)> = vec![
+            ((MessageType::SYS, "nakji.protoregistry.0_0_0.chain_Block".to_string()), Box::new(eth_block.clone())),
+            ((MessageType::SYS, "nakji.protoregistry.0_0_0.chain_Transaction".to_string()), Box::new(eth_transaction)),
+            ((MessageType::FCT, "nakji.protoregistry.0_0_0.chain_Block".to_string()), Box::new(eth_block.clone())),
+        ];
+
+        let topic_proto_messages = build_topic_proto_messages(topic_types);
+
+        let descriptor = descriptor_set(eth_block.descriptor_dyn().file_descriptor());
+        let expected = vec![
+            TopicProtoMsg {
+                message_type: MessageType::SYS,
+                topic_name: "nakji.protoregistry.0_0_0.chain_Block".to_string(),
+                proto_message_name: "nakji.evm.Block".to_string(),
+                descriptor: descriptor.clone(),
+            },
+            TopicProtoMsg {
+                message_type: MessageType::SYS,
+                topic_name: "nakji.protoregistry.0_0_0.chain_Transaction".to_string(),
+                proto_message_name: "nakji.evm.Transaction".to_string(),
+                descriptor: descriptor.clone(),
+            },
+            TopicProtoMsg {
+                message_type: MessageType::FCT,
+                topic_name: "nakji.protoregistry.0_0_0.chain_Block".to_string(),
+                proto_message_name: "nakji.evm.Block".to_string(),
+                descriptor,
+            },
+        ];
+
+        assert_eq!(topic_proto_messages, expected)
+    }
+
+    #[test]
+    fn test_descriptor_set() {
+        let eth_block = utils::build_block();
+
+        let bytes = descriptor_set(eth_block.descriptor_dyn().file_descriptor());
+
+        // output of protoc --include_imports for evm.proto
+        let expected: Vec<u8> = vec![10, 255, 1, 10, 31, 103, 111, 111, 103, 108, 101, 47, 112, 114, 111, 116, 111, 98, 117, 102, 47, 116, 105, 109, 101, 115, 116, 97, 109, 112, 46, 112, 114, 111, 116, 111, 18, 15, 103, 111, 111, 103, 108, 101, 46, 112, 114, 111, 116, 111, 98, 117, 102, 34, 59, 10, 9, 84, 105, 109, 101, 115, 116, 97, 109, 112, 18, 24, 10, 7, 115, 101, 99, 111, 110, 100, 115, 24, 1, 32, 1, 40, 3, 82, 7, 115, 101, 99, 111, 110, 100, 115, 18, 20, 10, 5, 110, 97, 110, 111, 115, 24, 2, 32, 1, 40, 5, 82, 5, 110, 97, 110, 111, 115, 66, 133, 1, 10, 19, 99, 111, 109, 46, 103, 111, 111, 103, 108, 101, 46, 112, 114, 111, 116, 111, 98, 117, 102, 66, 14, 84, 105, 109, 101, 115, 116, 97, 109, 112, 80, 114, 111, 116, 111, 80, 1, 90, 50, 103, 111, 111, 103, 108, 101, 46, 103, 111, 108, 97, 110, 103, 46, 111, 114, 103, 47, 112, 114, 111, 116, 111, 98, 117, 102, 47, 116, 121, 112, 101, 115, 47, 107, 110, 111, 119, 110, 47, 116, 105, 109, 101, 115, 116, 97, 109, 112, 112, 98, 248, 1, 1, 162, 2, 3, 71, 80, 66, 170, 2, 30, 71, 111, 111, 103, 108, 101, 46, 80, 114, 111, 116, 111, 98, 117, 102, 46, 87, 101, 108, 108, 75, 110, 111, 119, 110, 84, 121, 112, 101, 115, 98, 6, 112, 114, 111, 116, 111, 51, 10, 217, 4, 10, 9, 101, 118, 109, 46, 112, 114, 111, 116, 111, 18, 9, 110, 97, 107, 106, 105, 46, 101, 118, 109, 26, 31, 103, 111, 111, 103, 108, 101, 47, 112, 114, 111, 116, 111, 98, 117, 102, 47, 116, 105, 109, 101, 115, 116, 97, 109, 112, 46, 112, 114, 111, 116, 111, 34, 199, 2, 10, 11, 84, 114, 97, 110, 115, 97, 99, 116, 105, 111, 110, 18, 42, 10, 2, 116, 115, 24, 1, 32, 1, 40, 11, 50, 26, 46, 103, 111, 111, 103, 108, 101, 46, 112, 114, 111, 116, 111, 98, 117, 102, 46, 84, 105, 109, 101, 115, 116, 97, 109, 112, 82, 2, 116, 115, 18, 18, 10, 4, 102, 114, 111, 109, 24, 2, 32, 1, 40, 12, 82, 4, 102, 114, 111, 109, 18, 18, 10, 4, 104, 97, 115, 104, 24, 3, 32, 1, 40, 9, 82, 4, 104, 97, 115, 104, 18, 18, 10, 4, 115, 105, 122, 101, 24, 4, 32, 1, 40, 1, 82, 4, 115, 105, 122, 101, 18, 35, 10, 13, 97, 99, 99, 111, 117, 110, 116, 95, 110, 111, 110, 99, 101, 24, 5, 32, 1, 40, 4, 82, 12, 97, 99, 99, 111, 117, 110, 116, 78, 111, 110, 99, 101, 18, 20, 10, 5, 112, 114, 105, 99, 101, 24, 6, 32, 1, 40, 4, 82, 5, 112, 114, 105, 99, 101, 18, 27, 10, 9, 103, 97, 115, 95, 108, 105, 109, 105, 116, 24, 7, 32, 1, 40, 4, 82, 8, 103, 97, 115, 76, 105, 109, 105, 116, 18, 28, 10, 9, 114, 101, 99, 105, 112, 105, 101, 110, 116, 24, 8, 32, 1, 40, 12, 82, 9, 114, 101, 99, 105, 112, 105, 101, 110, 116, 18, 22, 10, 6, 97, 109, 111, 117, 110, 116, 24, 9, 32, 1, 40, 4, 82, 6, 97, 109, 111, 117, 110, 116, 18, 24, 10, 7, 112, 97, 121, 108, 111, 97, 100, 24, 10, 32, 1, 40, 12, 82, 7, 112, 97, 121, 108, 111, 97, 100, 18, 12, 10, 1, 118, 24, 11, 32, 1, 40, 4, 82, 1, 118, 18, 12, 10, 1, 114, 24, 12, 32, 1, 40, 4, 82, 1, 114, 18, 12, 10, 1, 115, 24, 13, 32, 1, 40, 4, 82, 1, 115, 34, 205, 1, 10, 5, 66, 108, 111, 99, 107, 18, 42, 10, 2, 116, 115, 24, 1, 32, 1, 40, 11, 50, 26, 46, 103, 111, 111, 103, 108, 101, 46, 112, 114, 111, 116, 111, 98, 117, 102, 46, 84, 105, 109, 101, 115, 116, 97, 109, 112, 82, 2, 116, 115, 18, 18, 10, 4, 104, 97, 115, 104, 24, 2, 32, 1, 40, 9, 82, 4, 104, 97, 115, 104, 18, 30, 10, 10, 100, 105, 102, 102, 105, 99, 117, 108, 116, 121, 24, 3, 32, 1, 40, 4, 82, 10, 100, 105, 102, 102, 105, 99, 117, 108, 116, 121, 18, 22, 10, 6, 110, 117, 109, 98, 101, 114, 24, 4, 32, 1, 40, 4, 82, 6, 110, 117, 109, 98, 101, 114, 18, 27, 10, 9, 103, 97, 115, 95, 108, 105, 109, 105, 116, 24, 5, 32, 1, 40, 4, 82, 8, 103, 97, 115, 76, 105, 109, 105, 116, 18, 25, 10, 8, 103, 97, 115, 95, 117, 115, 101, 100, 24, 6, 32, 1, 40, 4, 82, 7, 103, 97, 115, 85, 115, 101, 100, 18, 20, 10, 5, 110, 111, 110, 99, 101, 24, 7, 32, 1, 40, 4, 82, 5, 110, 111, 110, 99, 101, 98, 6, 112, 114, 111, 116, 111, 51];
+
+        assert_eq!(bytes, expected);
+    }
+}
\ No newline at end of file
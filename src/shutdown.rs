@@ -0,0 +1,149 @@
+use std::io;
+use std::process;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+// time left to commit the in-flight transaction and flush the sink, below the default grace period of Kubernetes
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(25);
+// part of SHUTDOWN_TIMEOUT kept to abort the transaction when the flush fails or times out
+pub const SHUTDOWN_ABORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A received shutdown signal.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Signal {
+    pub name: &'static str,
+    // 128 + the signal number, as exited by a shell on a fatal signal
+    pub exit_code: i32,
+}
+
+/// Cancels the token when the process receives SIGTERM or SIGINT, and exits on the next one, so a stuck shutdown
+/// can be interrupted. The handlers are installed before this returns, so a signal can not be missed once the
+/// connector is created. Without a tokio runtime only `cancel` stops it.
+pub fn listen(token: CancellationToken) {
+    let handle = match Handle::try_current() {
+        Ok(handle) => handle,
+        Err(_) => return,
+    };
+    // registering a signal handler needs the runtime context
+    let _guard = handle.enter();
+
+    match Signals::new() {
+        Ok(mut signals) => {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            handle.spawn(async move {
+                loop {
+                    let signal = signals.recv().await;
+                    if sender.send(signal).is_err() {
+                        return;
+                    }
+                }
+            });
+            handle.spawn(watch(token, receiver, |code| process::exit(code)));
+        }
+        Err(err) => error!("failed to listen for shutdown signals: {}", err),
+    }
+}
+
+/// Cancels the token on the first signal, then calls `exit` on the next one, also when the token was cancelled
+/// without a signal.
+pub async fn watch(token: CancellationToken, mut signals: mpsc::UnboundedReceiver<Signal>, exit: impl FnOnce(i32)) {
+    tokio::select! {
+        // a signal received once the token is cancelled always exits
+        biased;
+        _ = token.cancelled() => {}
+        signal = signals.recv() => match signal {
+            Some(signal) => {
+                info!("received {}, shutting down", signal.name);
+                token.cancel();
+            }
+            None => return,
+        },
+    }
+
+    if let Some(signal) = signals.recv().await {
+        warn!("received {} while shutting down, exiting", signal.name);
+        exit(signal.exit_code);
+    }
+}
+
+#[cfg(unix)]
+struct Signals {
+    terminate: tokio::signal::unix::Signal,
+    interrupt: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    fn new() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        Ok(Signals { terminate: signal(SignalKind::terminate())?, interrupt: signal(SignalKind::interrupt())? })
+    }
+
+    async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.terminate.recv() => Signal { name: "SIGTERM", exit_code: 143 },
+            _ = self.interrupt.recv() => Signal { name: "SIGINT", exit_code: 130 },
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct Signals {
+    ctrl_c: tokio::signal::windows::CtrlC,
+}
+
+#[cfg(not(unix))]
+impl Signals {
+    fn new() -> io::Result<Self> {
+        Ok(Signals { ctrl_c: tokio::signal::windows::ctrl_c()? })
+    }
+
+    async fn recv(&mut self) -> Signal {
+        self.ctrl_c.recv().await;
+        Signal { name: "Ctrl-C", exit_code: 130 }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    const SIGTERM: Signal = Signal { name: "SIGTERM", exit_code: 143 };
+
+    #[tokio::test]
+    async fn cancels_token_on_first_signal_and_exits_on_second() {
+        let token = CancellationToken::new();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (exit, exited) = oneshot::channel();
+        let watcher = tokio::spawn(watch(token.clone(), receiver, move |code| exit.send(code).unwrap()));
+
+        sender.send(SIGTERM).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), token.cancelled()).await.unwrap();
+        assert!(!watcher.is_finished());
+
+        sender.send(SIGTERM).unwrap();
+        assert_eq!(exited.await, Ok(143));
+        watcher.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn exits_on_first_signal_once_cancelled() {
+        let token = CancellationToken::new();
+        token.cancel();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (exit, exited) = oneshot::channel();
+        let watcher = tokio::spawn(watch(token, receiver, move |code| exit.send(code).unwrap()));
+
+        sender.send(Signal { name: "SIGINT", exit_code: 130 }).unwrap();
+        assert_eq!(exited.await, Ok(130));
+        watcher.await.unwrap();
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, error, warn};
+use tokio::runtime::Handle;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::kafka_utils::{Partitioner, Record};
+use crate::kafka_utils::chunk::record_size;
+
+use super::{Sink, SinkError};
+
+pub const BATCH_MAX_MESSAGES: usize = 1000;
+pub const BATCH_MAX_BYTES: usize = 10 * 1024 * 1024;
+pub const BATCH_MAX_DELAY: Duration = Duration::from_secs(1);
+pub const BATCH_MAX_BUFFERED_BYTES: usize = 100 * 1024 * 1024;
+
+// the time threshold is checked this many times per max_delay, but at most once per BATCH_TIMER_MIN_TICK
+const BATCH_TIMER_TICKS: u32 = 4;
+const BATCH_TIMER_MIN_TICK: Duration = Duration::from_millis(1);
+
+/// Thresholds of `BatchingSink`, read from `sink.batch` in config.yaml. A batch is written as soon as one is reached.
+#[derive(Debug, PartialEq, Clone)]
+pub struct BatchConfig {
+    pub max_messages: usize,
+    pub max_bytes: usize,
+    pub max_delay: Duration,
+    /// Writes are rejected with `SinkError::BufferFull` once the records kept after failed batches take this much.
+    pub max_buffered_bytes: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            max_messages: BATCH_MAX_MESSAGES,
+            max_bytes: BATCH_MAX_BYTES,
+            max_delay: BATCH_MAX_DELAY,
+            max_buffered_bytes: BATCH_MAX_BUFFERED_BYTES,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Buffer {
+    records: Vec<Record>,
+    bytes: usize,
+    since: Option<Instant>,
+}
+
+struct Shared {
+    inner: Box<dyn Sink>,
+    config: BatchConfig,
+    buffer: Mutex<Buffer>,
+}
+
+/// Accumulates the records of several writes and writes them to the inner sink as one batch, e.g. one Kafka
+/// transaction for many blocks. Buffered records are written when the sink is dropped, but only `flush` waits for
+/// them, so it has to be called before the process exits.
+pub struct BatchingSink {
+    shared: Arc<Shared>,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl BatchingSink {
+    /// The time threshold is only checked on writes when there is no tokio runtime to run the timer.
+    pub fn new(config: BatchConfig, inner: Box<dyn Sink>) -> Self {
+        let shared = Arc::new(Shared { inner, config, buffer: Mutex::new(Buffer::default()) });
+        let timer = Handle::try_current().ok().map(|handle| handle.spawn(run_timer(Arc::downgrade(&shared))));
+        BatchingSink { shared, timer }
+    }
+}
+
+impl Shared {
+    // when nothing of the batch was written the records stay at the front of the buffer, so the next attempt writes
+    // them again in order, and the error is returned as SinkError::KeptForRetry. Other errors are returned as they are
+    // and the records are dropped, since writing them again would duplicate them or fail the same way
+    async fn write_buffer(&self, buffer: &mut Buffer) -> Result<(), SinkError> {
+        if buffer.records.is_empty() {
+            return Ok(());
+        }
+
+        let records = std::mem::take(&mut buffer.records);
+        let count = records.len();
+        match self.inner.write(records.clone()).await {
+            Ok(_) => {
+                debug!("wrote batch of {} records", count);
+                buffer.bytes = 0;
+                buffer.since = None;
+                Ok(())
+            }
+            // the inner sink keeps the batch itself, e.g. the outbox
+            Err(err @ SinkError::KeptForRetry(_)) => {
+                buffer.bytes = 0;
+                buffer.since = None;
+                Err(err)
+            }
+            Err(err) if err.is_aborted() => {
+                buffer.records = records;
+                Err(SinkError::KeptForRetry(Box::new(err)))
+            }
+            Err(err) => {
+                error!("dropping batch of {} records: {}", count, err);
+                buffer.bytes = 0;
+                buffer.since = None;
+                Err(err)
+            }
+        }
+    }
+
+    fn is_due(&self, buffer: &Buffer) -> bool {
+        buffer.records.len() >= self.config.max_messages
+            || buffer.bytes >= self.config.max_bytes
+            || buffer.since.map(|since| since.elapsed() >= self.config.max_delay).unwrap_or(false)
+    }
+}
+
+async fn run_timer(shared: Weak<Shared>) {
+    let tick = shared.upgrade().map(|s| s.config.max_delay / BATCH_TIMER_TICKS).unwrap_or_default().max(BATCH_TIMER_MIN_TICK);
+    loop {
+        tokio::time::sleep(tick).await;
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+
+        let mut buffer = shared.buffer.lock().await;
+        if shared.is_due(&buffer) {
+            if let Err(err) = shared.write_buffer(&mut buffer).await {
+                error!("failed to write batch of {} records: {}", buffer.records.len(), err);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for BatchingSink {
+    /// Buffers the records, writing the batch if a threshold is reached. A batch which was not written is kept for the
+    /// next attempt with `SinkError::KeptForRetry`, so the records must not be written again. Any other error, e.g. a
+    /// fenced producer, drops the batch.
+    async fn write(&self, records: Vec<Record>) -> Result<(), SinkError> {
+        let mut buffer = self.shared.buffer.lock().await;
+
+        let bytes = records.iter().map(record_size).sum::<usize>();
+        if buffer.bytes + bytes > self.shared.config.max_buffered_bytes {
+            return Err(SinkError::BufferFull { buffered_bytes: buffer.bytes, max_bytes: self.shared.config.max_buffered_bytes });
+        }
+        buffer.bytes += bytes;
+        buffer.records.extend(records);
+        buffer.since.get_or_insert_with(Instant::now);
+
+        if self.shared.is_due(&buffer) {
+            self.shared.write_buffer(&mut buffer).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), SinkError> {
+        let mut buffer = self.shared.buffer.lock().await;
+        self.shared.write_buffer(&mut buffer).await?;
+        self.shared.inner.flush().await
+    }
+
+    fn set_schema_hashes(&self, schema_hashes: &HashMap<String, String>) {
+        self.shared.inner.set_schema_hashes(schema_hashes);
+    }
+
+    fn set_partitioner(&self, partitioner: Arc<dyn Partitioner>) {
+        self.shared.inner.set_partitioner(partitioner);
+    }
+
+    async fn reinitialize(&self) -> Result<(), SinkError> {
+        self.shared.inner.reinitialize().await
+    }
+}
+
+impl Drop for BatchingSink {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            timer.abort();
+        }
+
+        let shared = self.shared.clone();
+        match Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move {
+                    let mut buffer = shared.buffer.lock().await;
+                    if let Err(err) = shared.write_buffer(&mut buffer).await {
+                        error!("failed to write batch of {} records on drop: {}", buffer.records.len(), err);
+                    }
+                });
+            }
+            Err(_) => {
+                if let Ok(buffer) = shared.buffer.try_lock() {
+                    if !buffer.records.is_empty() {
+                        warn!("dropping {} buffered records, flush was not called", buffer.records.len());
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use crate::kafka_utils::ProducerError;
+    use crate::kafka_utils::proto_test::utils::build_record;
+    use crate::sink::MemorySink;
+
+    use super::*;
+
+    // fails the first write after one of its records was sent
+    #[derive(Default, Clone)]
+    struct PartialSink {
+        writes: Arc<StdMutex<Vec<Vec<Record>>>>,
+    }
+
+    #[async_trait]
+    impl Sink for PartialSink {
+        async fn write(&self, records: Vec<Record>) -> Result<(), SinkError> {
+            let mut writes = self.writes.lock().unwrap();
+            writes.push(records);
+            match writes.len() {
+                1 => Err(ProducerError::PartiallySent { sent: 1, source: Box::new(ProducerError::Send("topic".to_string())) }.into()),
+                _ => Ok(()),
+            }
+        }
+
+        async fn flush(&self) -> Result<(), SinkError> {
+            Ok(())
+        }
+    }
+
+    fn keys(batch: &[Record]) -> Vec<String> {
+        batch.iter().map(|r| String::from_utf8(r.key.clone()).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn writes_batch_when_count_or_bytes_reached() {
+        let inner = MemorySink::new();
+        let config = BatchConfig { max_messages: 3, max_bytes: 1000, max_delay: Duration::from_secs(60), ..Default::default() };
+        let sink = BatchingSink::new(config, Box::new(inner.clone()));
+
+        sink.write(vec![build_record("1", vec![0; 100]), build_record("2", vec![0; 100])]).await.unwrap();
+        assert!(inner.transactions().is_empty());
+        sink.write(vec![build_record("3", vec![0; 100])]).await.unwrap();
+        assert_eq!(inner.transactions().iter().map(|b| keys(b)).collect::<Vec<_>>(), vec![vec!["1", "2", "3"]]);
+
+        let large = build_record("4", vec![0; 1000]);
+        sink.write(vec![large]).await.unwrap();
+        assert_eq!(inner.transactions().len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writes_batch_after_delay() {
+        let inner = MemorySink::new();
+        let config = BatchConfig { max_messages: 100, max_bytes: 100_000, max_delay: Duration::from_millis(40), ..Default::default() };
+        let sink = BatchingSink::new(config, Box::new(inner.clone()));
+
+        sink.write(vec![build_record("1", vec![0; 100])]).await.unwrap();
+        tokio::time::advance(Duration::from_millis(30)).await;
+        tokio::task::yield_now().await;
+        assert!(inner.transactions().is_empty());
+
+        tokio::time::advance(Duration::from_millis(20)).await;
+        tokio::task::yield_now().await;
+        assert_eq!(inner.transactions().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_batch_is_kept_for_flush() {
+        let inner = MemorySink::new();
+        let config = BatchConfig { max_messages: 2, max_bytes: 100_000, max_delay: Duration::from_secs(60), max_buffered_bytes: 350 };
+        let sink = BatchingSink::new(config, Box::new(inner.clone()));
+
+        inner.abort_next(2);
+        sink.write(vec![build_record("1", vec![0; 100])]).await.unwrap();
+        let err = sink.write(vec![build_record("2", vec![0; 100])]).await.unwrap_err();
+        assert!(matches!(&err, SinkError::KeptForRetry(source) if source.is_aborted()));
+        assert!(!err.is_aborted());
+        assert!(matches!(sink.write(vec![build_record("3", vec![0; 100])]).await, Err(SinkError::KeptForRetry(_))));
+        let err = sink.write(vec![build_record("4", vec![0; 100])]).await.unwrap_err();
+        assert!(matches!(err, SinkError::BufferFull { .. }));
+        assert!(err.is_aborted());
+        sink.flush().await.unwrap();
+
+        assert_eq!(inner.transactions().iter().map(|b| keys(b)).collect::<Vec<_>>(), vec![vec!["1", "2", "3"]]);
+    }
+
+    #[tokio::test]
+    async fn fenced_batch_is_dropped() {
+        let inner = MemorySink::new();
+        let config = BatchConfig { max_messages: 2, max_bytes: 100_000, max_delay: Duration::from_secs(60), ..Default::default() };
+        let sink = BatchingSink::new(config, Box::new(inner.clone()));
+
+        inner.fence();
+        sink.write(vec![build_record("1", vec![0; 100])]).await.unwrap();
+        let err = sink.write(vec![build_record("2", vec![0; 100])]).await.unwrap_err();
+        assert!(matches!(err, SinkError::Producer(ProducerError::Fenced)));
+
+        inner.unfence();
+        sink.write(vec![build_record("3", vec![0; 100])]).await.unwrap();
+        sink.flush().await.unwrap();
+        assert_eq!(inner.transactions().iter().map(|b| keys(b)).collect::<Vec<_>>(), vec![vec!["3"]]);
+    }
+
+    #[tokio::test]
+    async fn partially_sent_batch_is_not_written_again() {
+        let inner = PartialSink::default();
+        let config = BatchConfig { max_messages: 2, max_bytes: 100_000, max_delay: Duration::from_secs(60), ..Default::default() };
+        let sink = BatchingSink::new(config, Box::new(inner.clone()));
+
+        sink.write(vec![build_record("1", vec![0; 100])]).await.unwrap();
+        let err = sink.write(vec![build_record("2", vec![0; 100])]).await.unwrap_err();
+        assert!(matches!(err, SinkError::Producer(ProducerError::PartiallySent { sent: 1, .. })));
+
+        sink.write(vec![build_record("3", vec![0; 100])]).await.unwrap();
+        sink.flush().await.unwrap();
+        let writes = inner.writes.lock().unwrap().iter().map(|b| keys(b)).collect::<Vec<_>>();
+        assert_eq!(writes, vec![vec!["1", "2"], vec!["3"]]);
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+use crate::kafka_utils::Record;
+
+use super::{blocking, record_to_json, Sink, SinkError};
+
+pub const FILE_SINK_PATH: &str = ".nakji/records.jsonl";
+
+/// Appends every record as a line of JSON to a file. Existing content is never rewritten. The file is written on
+/// the blocking thread pool of tokio.
+pub struct FileSink {
+    file: Arc<Mutex<File>>,
+}
+
+impl FileSink {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        if let Some(dir) = path.as_ref().parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileSink { file: Arc::new(Mutex::new(file)) })
+    }
+}
+
+#[async_trait]
+impl Sink for FileSink {
+    async fn write(&self, records: Vec<Record>) -> Result<(), SinkError> {
+        // a batch is written with a single call so concurrent batches are not interleaved
+        let mut lines = Vec::new();
+        for record in &records {
+            serde_json::to_writer(&mut lines, &record_to_json(record)).expect("failed to serialize record");
+            lines.push(b'\n');
+        }
+        let file = self.file.clone();
+        Ok(blocking(move || file.lock().unwrap().write_all(&lines)).await?)
+    }
+
+    async fn flush(&self) -> Result<(), SinkError> {
+        let file = self.file.clone();
+        Ok(blocking(move || file.lock().unwrap().sync_data()).await?)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use crate::kafka_utils::proto_test::utils;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn appends_records() {
+        let path = env::temp_dir().join("nakji-connector-test").join("records.jsonl");
+        let _ = fs::remove_file(&path);
+
+        FileSink::open(&path).unwrap().write(vec![utils::build_record("ethereum.1", vec![8, 1])]).await.unwrap();
+        let sink = FileSink::open(&path).unwrap();
+        sink.write(vec![utils::build_record("ethereum.2", vec![8, 1]), utils::build_record("ethereum.3", vec![8, 1])]).await.unwrap();
+        sink.flush().await.unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let keys: Vec<String> = content.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["key"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(keys, vec!["ethereum.1", "ethereum.2", "ethereum.3"]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::kafka_utils::{Partitioner, Producer, Record};
+
+use super::{Sink, SinkError};
+
+/// Produces the records to Kafka, with the guarantee of `kafka.producer.mode`.
+pub struct KafkaSink {
+    producer: Producer,
+}
+
+impl KafkaSink {
+    pub fn new(producer: Producer) -> Self {
+        KafkaSink { producer }
+    }
+
+    pub fn producer(&self) -> &Producer {
+        &self.producer
+    }
+}
+
+#[async_trait]
+impl Sink for KafkaSink {
+    async fn write(&self, records: Vec<Record>) -> Result<(), SinkError> {
+        Ok(self.producer.produce_messages(records).await?)
+    }
+
+    async fn flush(&self) -> Result<(), SinkError> {
+        Ok(self.producer.flush().await?)
+    }
+
+    fn set_schema_hashes(&self, schema_hashes: &HashMap<String, String>) {
+        self.producer.set_schema_hashes(schema_hashes);
+    }
+
+    fn set_partitioner(&self, partitioner: Arc<dyn Partitioner>) {
+        self.producer.set_partitioner(partitioner);
+    }
+
+    async fn reinitialize(&self) -> Result<(), SinkError> {
+        Ok(self.producer.reinitialize().await?)
+    }
+}
@@ -0,0 +1,158 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use protobuf::MessageFull;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+
+use crate::kafka_utils::{ProducerError, Record};
+
+use super::{Sink, SinkError};
+
+#[derive(Default)]
+struct State {
+    committed: Vec<Vec<Record>>,
+    aborted: Vec<Vec<Record>>,
+    aborts: usize,
+    fenced: bool,
+}
+
+/// Sink keeping every committed batch in memory, so connectors can be tested without Kafka.
+/// Aborts and fencing can be simulated to exercise the error handling of a connector.
+///
+/// ```ignore
+/// let sink = MemorySink::new();
+/// connector.sink = Box::new(sink.clone());
+/// // ... run the connector
+/// sink.assert_received_with_key("test.fct.nakji.ethereum.0_1_0.evm_Block", "ethereum.Block", 1);
+/// ```
+#[derive(Default, Clone)]
+pub struct MemorySink {
+    state: Arc<Mutex<State>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Aborts the next `times` batches, as if their transaction failed to commit.
+    pub fn abort_next(&self, times: usize) {
+        self.state.lock().unwrap().aborts += times;
+    }
+
+    /// Fails every following batch with `ProducerError::Fenced`, until `unfence` is called.
+    pub fn fence(&self) {
+        self.state.lock().unwrap().fenced = true;
+    }
+
+    pub fn unfence(&self) {
+        self.state.lock().unwrap().fenced = false;
+    }
+
+    /// Committed batches, in the order they were written.
+    pub fn transactions(&self) -> Vec<Vec<Record>> {
+        self.state.lock().unwrap().committed.clone()
+    }
+
+    /// Batches which were rejected by `abort_next`.
+    pub fn aborted(&self) -> Vec<Vec<Record>> {
+        self.state.lock().unwrap().aborted.clone()
+    }
+
+    /// Every committed record.
+    pub fn records(&self) -> Vec<Record> {
+        self.transactions().into_iter().flatten().collect()
+    }
+
+    /// Committed records of a topic.
+    pub fn records_for(&self, topic: &str) -> Vec<Record> {
+        self.records().into_iter().filter(|r| r.topic == topic).collect()
+    }
+
+    /// Decodes the committed protobuf payloads of a topic.
+    pub fn messages_for<T: MessageFull>(&self, topic: &str) -> Vec<T> {
+        self.records_for(topic)
+            .iter()
+            .map(|r| T::parse_from_bytes(&r.payload).expect("failed to decode record payload"))
+            .collect()
+    }
+
+    pub fn assert_received(&self, topic: &str, count: usize) {
+        let received = self.records_for(topic).len();
+        assert_eq!(received, count, "topic {} received {} messages, expected {}", topic, received, count);
+    }
+
+    pub fn assert_received_with_key(&self, topic: &str, key: &str, count: usize) {
+        let received = self.records_for(topic).iter().filter(|r| r.key == key.as_bytes()).count();
+        assert_eq!(received, count, "topic {} received {} messages with key {}, expected {}", topic, received, key, count);
+    }
+}
+
+#[async_trait]
+impl Sink for MemorySink {
+    async fn write(&self, records: Vec<Record>) -> Result<(), SinkError> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.fenced {
+            return Err(ProducerError::Fenced.into());
+        }
+        if state.aborts > 0 {
+            state.aborts -= 1;
+            state.aborted.push(records);
+            return Err(ProducerError::Aborted(KafkaError::MessageProduction(RDKafkaErrorCode::RequestTimedOut)).into());
+        }
+
+        state.committed.push(records);
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), SinkError> {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::kafka_utils::proto_test::{evm::Block, utils};
+    use crate::kafka_utils::proto_test::utils::BLOCK_TOPIC;
+
+    use super::*;
+
+    fn build_record(key: &str) -> Record {
+        utils::build_record(key, protobuf::Message::write_to_bytes(&utils::build_block()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn records_committed_batches() {
+        let sink = MemorySink::new();
+
+        sink.write(vec![build_record("ethereum.1"), build_record("ethereum.2")]).await.unwrap();
+        sink.clone().write(vec![build_record("ethereum.1")]).await.unwrap();
+
+        assert_eq!(sink.transactions().len(), 2);
+        sink.assert_received(BLOCK_TOPIC, 3);
+        sink.assert_received_with_key(BLOCK_TOPIC, "ethereum.1", 2);
+        sink.assert_received("test.fct.nakji.ethereum.0_1_0.evm_Transaction", 0);
+        assert_eq!(sink.messages_for::<Block>(BLOCK_TOPIC), vec![utils::build_block(); 3]);
+    }
+
+    #[tokio::test]
+    async fn simulates_abort_and_fencing() {
+        let sink = MemorySink::new();
+
+        sink.abort_next(1);
+        let err = sink.write(vec![build_record("ethereum.1")]).await.unwrap_err();
+        assert!(err.is_aborted());
+        assert_eq!(sink.aborted().len(), 1);
+
+        sink.fence();
+        let err = sink.write(vec![build_record("ethereum.2")]).await.unwrap_err();
+        assert!(matches!(err, SinkError::Producer(ProducerError::Fenced)));
+
+        sink.unfence();
+        sink.write(vec![build_record("ethereum.3")]).await.unwrap();
+        sink.assert_received(BLOCK_TOPIC, 1);
+        sink.assert_received_with_key(BLOCK_TOPIC, "ethereum.3", 1);
+    }
+}
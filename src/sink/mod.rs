@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+
+use crate::kafka_utils::{Encoding, Partitioner, ProducerError, Record};
+
+pub mod batch;
+pub mod file;
+pub mod kafka;
+#[cfg(any(test, feature = "testing"))]
+pub mod memory;
+pub mod outbox;
+pub mod stdout;
+
+pub use batch::{BATCH_MAX_BUFFERED_BYTES, BATCH_MAX_BYTES, BATCH_MAX_DELAY, BATCH_MAX_MESSAGES, BatchConfig, BatchingSink};
+pub use file::{FILE_SINK_PATH, FileSink};
+pub use kafka::KafkaSink;
+#[cfg(any(test, feature = "testing"))]
+pub use memory::MemorySink;
+pub use outbox::{OUTBOX_MAX_BYTES, OUTBOX_PATH, OutboxConfig, OutboxMetrics, OutboxSink};
+pub use stdout::StdoutSink;
+
+/// Destination of the produced records, selected with `sink.type` in config.yaml.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub enum SinkConfig {
+    #[default]
+    Kafka,
+    // pretty-printed JSON, for local debugging
+    Stdout,
+    // one JSON record per line appended to the file
+    File(PathBuf),
+}
+
+#[derive(Error, Debug)]
+pub enum SinkError {
+    #[error(transparent)]
+    Producer(#[from] ProducerError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("outbox is full, {pending_bytes} bytes are pending with a limit of {max_bytes}")]
+    OutboxFull { pending_bytes: u64, max_bytes: u64 },
+    #[error("batch buffer is full, {buffered_bytes} bytes are buffered with a limit of {max_bytes}")]
+    BufferFull { buffered_bytes: usize, max_bytes: usize },
+    /// The batch was not written to the backend but is kept by the sink, so it must not be written again.
+    #[error("batch is kept for retry: {0}")]
+    KeptForRetry(#[source] Box<SinkError>),
+}
+
+impl SinkError {
+    /// Whether nothing of the batch was written, so it can be sent again.
+    pub fn is_aborted(&self) -> bool {
+        match self {
+            SinkError::Producer(err) => err.is_aborted(),
+            SinkError::OutboxFull { .. } | SinkError::BufferFull { .. } => true,
+            SinkError::Io(_) | SinkError::KeptForRetry(_) => false,
+        }
+    }
+}
+
+/// Backend the connector produces through.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    /// Writes a batch of records, atomically if the backend supports transactions.
+    async fn write(&self, records: Vec<Record>) -> Result<(), SinkError>;
+
+    /// Waits until every record written so far is persisted.
+    async fn flush(&self) -> Result<(), SinkError>;
+
+    /// Descriptor hashes of the registered schemas, keyed by protobuf full name.
+    fn set_schema_hashes(&self, _schema_hashes: &HashMap<String, String>) {}
+
+    /// Partitioner of the records with an entity, only used by backends with partitions.
+    fn set_partitioner(&self, _partitioner: Arc<dyn Partitioner>) {}
+
+    /// Recreates the backend after a fatal error, e.g. a fenced Kafka producer.
+    async fn reinitialize(&self) -> Result<(), SinkError> {
+        Ok(())
+    }
+}
+
+// a sink shared with its owner, e.g. the outbox of the connector
+#[async_trait]
+impl<S: Sink + ?Sized> Sink for Arc<S> {
+    async fn write(&self, records: Vec<Record>) -> Result<(), SinkError> {
+        self.as_ref().write(records).await
+    }
+
+    async fn flush(&self) -> Result<(), SinkError> {
+        self.as_ref().flush().await
+    }
+
+    fn set_schema_hashes(&self, schema_hashes: &HashMap<String, String>) {
+        self.as_ref().set_schema_hashes(schema_hashes);
+    }
+
+    fn set_partitioner(&self, partitioner: Arc<dyn Partitioner>) {
+        self.as_ref().set_partitioner(partitioner);
+    }
+
+    async fn reinitialize(&self) -> Result<(), SinkError> {
+        self.as_ref().reinitialize().await
+    }
+}
+
+// runs file system calls of the file backed sinks on the blocking thread pool, so they do not stall the async runtime
+pub(crate) async fn blocking<T: Send + 'static>(f: impl FnOnce() -> std::io::Result<T> + Send + 'static) -> std::io::Result<T> {
+    tokio::task::spawn_blocking(f).await.map_err(std::io::Error::other)?
+}
+
+/// JSON representation of a record used by the stdout and file sinks. The payload is decoded back into the
+/// proto3 JSON mapping when possible, otherwise it is written hex encoded.
+pub fn record_to_json(record: &Record) -> Value {
+    let headers: Map<String, Value> = record.headers.iter()
+        .map(|(k, v)| (k.to_string(), Value::from(String::from_utf8_lossy(v))))
+        .collect();
+
+    json!({
+        "topic": record.topic,
+        "key": String::from_utf8_lossy(&record.key),
+        "headers": headers,
+        "timestamp": record.timestamp.or(record.event_time),
+        "value": decode_payload(record),
+    })
+}
+
+fn decode_payload(record: &Record) -> Value {
+    let decoded = match record.encoding {
+        Encoding::Json => serde_json::from_slice(&record.payload).ok(),
+        Encoding::Protobuf => record.descriptor.as_ref()
+            .and_then(|d| d.parse_from_bytes(&record.payload).ok())
+            .and_then(|m| protobuf_json_mapping::print_to_string(m.as_ref()).ok())
+            .and_then(|s| serde_json::from_str(&s).ok()),
+    };
+    decoded.unwrap_or_else(|| Value::from(hex::encode(&record.payload)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use semver::Version;
+
+    use crate::kafka_utils::{Env, IntoRecord, MessageType, RecordConfig, Topic, TopicEncoding, TypedMessage};
+    use crate::kafka_utils::key::Key;
+    use crate::kafka_utils::proto_test::{evm::Block, utils};
+
+    use super::*;
+
+    fn build_record(encoding: Encoding) -> Record {
+        let topic = Topic::for_message::<Block>(Env::Test, MessageType::FCT, "nakji".to_string(), "ethereum".to_string(), Version::new(0, 1, 0));
+        let key = Key::new("ethereum".to_string(), "Block".to_string());
+        let config = RecordConfig { encoding: TopicEncoding { default: encoding, topics: HashMap::new() }, ..Default::default() };
+        TypedMessage::new(topic, key, utils::build_block())
+            .with_header("trace-id", "abc")
+            .into_record(&config)
+            .unwrap()
+    }
+
+    #[test]
+    fn record_to_json_decodes_payload() {
+        let expected_value: Value = serde_json::from_str(&protobuf_json_mapping::print_to_string(&utils::build_block()).unwrap()).unwrap();
+
+        for encoding in [Encoding::Protobuf, Encoding::Json] {
+            let value = record_to_json(&build_record(encoding));
+
+            assert_eq!(value["topic"], "test.fct.nakji.ethereum.0_1_0.evm_Block");
+            assert_eq!(value["key"], "ethereum.Block");
+            assert_eq!(value["headers"]["trace-id"], "abc");
+            assert_eq!(value["value"], expected_value);
+        }
+    }
+
+    #[test]
+    fn record_to_json_without_descriptor() {
+        let mut record = build_record(Encoding::Protobuf);
+        record.descriptor = None;
+
+        let value = record_to_json(&record);
+
+        assert_eq!(value["value"], hex::encode(&record.payload));
+    }
+}
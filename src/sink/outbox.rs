@@ -0,0 +1,548 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::kafka_utils::{Headers, Partitioner, ProducerError, Record};
+
+use super::{blocking, Sink, SinkError};
+
+pub const OUTBOX_PATH: &str = ".nakji/outbox.log";
+pub const OUTBOX_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+
+// after a replay failed with a transient error, writes only append to the log until the interval has passed;
+// flush always replays
+const OUTBOX_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// the log is rewritten without the replayed batches once they take this much space
+const OUTBOX_COMPACT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Location and size limit of the outbox, read from `sink.outbox` in config.yaml.
+#[derive(Debug, PartialEq, Clone)]
+pub struct OutboxConfig {
+    pub path: PathBuf,
+    pub max_bytes: u64,
+}
+
+/// Counters of the outbox, see `OutboxSink::metrics`.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct OutboxMetrics {
+    pub pending_batches: usize,
+    pub pending_bytes: u64,
+    pub appended_batches: u64,
+    pub replayed_batches: u64,
+    pub replay_failures: u64,
+    pub skipped_batches: u64,
+}
+
+// a batch as one line of the log, binary fields are hex encoded
+#[derive(Serialize, Deserialize)]
+struct StoredRecord {
+    topic: String,
+    key: String,
+    payload: String,
+    encoding: String,
+    proto_name: String,
+    headers: Vec<(String, String)>,
+    timestamp: Option<i64>,
+    event_time: Option<i64>,
+    // missing in logs written before partitioning by entity
+    #[serde(default)]
+    entity: Option<String>,
+}
+
+struct Outbox {
+    path: PathBuf,
+    // shared with the blocking tasks appending to it
+    file: Arc<File>,
+    // offset of the first pending batch in the log, the batches before it are replayed
+    committed: u64,
+    // lines of the pending batches, in order
+    pending: VecDeque<Vec<u8>>,
+    retry_at: Option<Instant>,
+    metrics: OutboxMetrics,
+}
+
+/// Durable write-ahead log in front of another sink. Every batch is appended and synced to a local file before
+/// `write` returns, then replayed to the inner sink in order, so batches written while Kafka is unavailable are
+/// not lost. Batches left from a previous run are replayed first.
+///
+/// The offset of the replayed batches is kept next to the log in a `.offset` file, which is not synced: after a
+/// crash, the last replayed batches can be replayed again. The log is truncated once every batch is replayed.
+///
+/// The file is read and written on the blocking thread pool of tokio, except in `open`.
+pub struct OutboxSink {
+    inner: Box<dyn Sink>,
+    max_bytes: u64,
+    outbox: Mutex<Outbox>,
+}
+
+impl OutboxSink {
+    /// Loads the batches left from a previous run. It blocks on the file system, like the rest of the connector
+    /// setup, and is only called once at startup.
+    pub fn open(path: impl AsRef<Path>, max_bytes: u64, inner: Box<dyn Sink>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let pending = load(&path)?;
+        if !pending.is_empty() {
+            warn!("outbox {:?} has {} batches left from a previous run", path, pending.len());
+        }
+        // rewrite the log without the replayed batches, so an incomplete last line does not corrupt the following ones
+        write_offset(&path, 0)?;
+        rewrite(&path, &pending)?;
+        let file = Arc::new(OpenOptions::new().append(true).open(&path)?);
+
+        let metrics = OutboxMetrics {
+            pending_batches: pending.len(),
+            pending_bytes: pending.iter().map(|line| line.len() as u64).sum(),
+            ..Default::default()
+        };
+
+        Ok(OutboxSink {
+            inner,
+            max_bytes,
+            outbox: Mutex::new(Outbox { path, file, committed: 0, pending, retry_at: None, metrics }),
+        })
+    }
+
+    pub async fn metrics(&self) -> OutboxMetrics {
+        self.outbox.lock().await.metrics.clone()
+    }
+
+    /// Removes the first pending batch without writing it, e.g. after `write` failed with an error which replaying
+    /// it again can not fix. Returns its records, to write them to a dead letter topic, or `None` if no batch is
+    /// pending. The records of a corrupted batch are lost, an empty batch is returned for it.
+    pub async fn skip_batch(&self) -> Result<Option<Vec<Record>>, SinkError> {
+        let mut outbox = self.outbox.lock().await;
+        let records = match outbox.pending.front() {
+            Some(line) => decode_batch(line).unwrap_or_default(),
+            None => return Ok(None),
+        };
+        warn!("skipping outbox batch of {} records", records.len());
+        outbox.commit(1).await?;
+        outbox.metrics.skipped_batches += 1;
+        outbox.retry_at = None;
+        Ok(Some(records))
+    }
+
+    // replays the pending batches in order, stopping at the first failure. `appended` are the records of the only
+    // pending batch, so they do not have to be decoded from the log.
+    async fn replay(&self, outbox: &mut Outbox, mut appended: Option<Vec<Record>>) -> Result<(), SinkError> {
+        let mut replayed = 0;
+        let mut result = Ok(());
+
+        for line in &outbox.pending {
+            let records = match appended.take() {
+                Some(records) => records,
+                None => match decode_batch(line) {
+                    Some(records) => records,
+                    None => {
+                        result = Err(io::Error::new(ErrorKind::InvalidData, "corrupted outbox entry").into());
+                        break;
+                    }
+                },
+            };
+            match self.inner.write(records).await {
+                Ok(_) => replayed += 1,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+
+        if replayed > 0 {
+            outbox.commit(replayed).await?;
+            outbox.metrics.replayed_batches += replayed as u64;
+            debug!("replayed {} batches from the outbox", replayed);
+        }
+
+        match &result {
+            Ok(_) => outbox.retry_at = None,
+            Err(err) => {
+                error!("failed to replay outbox, {} batches pending: {}", outbox.pending.len(), err);
+                outbox.metrics.replay_failures += 1;
+                // fatal errors are returned by every write until the sink is reinitialized or the batch skipped
+                outbox.retry_at = if is_transient(err) { Some(Instant::now() + OUTBOX_RETRY_INTERVAL) } else { None };
+            }
+        }
+        result
+    }
+}
+
+impl Outbox {
+    // removes the first batches once they are written to the inner sink or skipped
+    async fn commit(&mut self, count: usize) -> io::Result<()> {
+        let bytes: u64 = self.pending.drain(..count).map(|line| line.len() as u64).sum();
+        self.committed += bytes;
+        self.metrics.pending_batches = self.pending.len();
+        self.metrics.pending_bytes -= bytes;
+
+        let path = self.path.clone();
+        if self.pending.is_empty() {
+            let file = self.file.clone();
+            // replaying a batch twice after a crash is fine, skipping one is not
+            blocking(move || {
+                write_offset(&path, 0)?;
+                file.set_len(0)
+            }).await?;
+            self.committed = 0;
+        } else if self.committed >= OUTBOX_COMPACT_BYTES {
+            let pending = self.pending.clone();
+            self.file = blocking(move || {
+                write_offset(&path, 0)?;
+                rewrite(&path, &pending)?;
+                Ok(Arc::new(OpenOptions::new().append(true).open(&path)?))
+            }).await?;
+            self.committed = 0;
+        } else {
+            let committed = self.committed;
+            blocking(move || write_offset(&path, committed)).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for OutboxSink {
+    /// Returns once the batch is durably in the log. Transient replay failures are only logged here, see `flush`.
+    /// Other failures are returned as `SinkError::KeptForRetry`, the batch stays in the log either way.
+    async fn write(&self, records: Vec<Record>) -> Result<(), SinkError> {
+        let mut outbox = self.outbox.lock().await;
+
+        let line = encode_batch(&records);
+        let size = line.len() as u64;
+        if outbox.metrics.pending_bytes + size > self.max_bytes {
+            return Err(SinkError::OutboxFull { pending_bytes: outbox.metrics.pending_bytes, max_bytes: self.max_bytes });
+        }
+
+        let file = outbox.file.clone();
+        let end = outbox.committed + outbox.metrics.pending_bytes;
+        let line = match blocking(move || append(&file, &line).map(|_| line)).await {
+            Ok(line) => line,
+            Err(err) => {
+                // an incomplete line would corrupt the batches appended after it
+                let file = outbox.file.clone();
+                if let Err(truncate_err) = blocking(move || file.set_len(end)).await {
+                    error!("failed to truncate outbox {:?} after a failed append: {}", outbox.path, truncate_err);
+                }
+                return Err(err.into());
+            }
+        };
+        outbox.pending.push_back(line);
+        outbox.metrics.appended_batches += 1;
+        outbox.metrics.pending_batches += 1;
+        outbox.metrics.pending_bytes += size;
+
+        if outbox.retry_at.map(|at| Instant::now() >= at).unwrap_or(true) {
+            let appended = if outbox.pending.len() == 1 { Some(records) } else { None };
+            match self.replay(&mut outbox, appended).await {
+                Err(err) if !is_transient(&err) => return Err(SinkError::KeptForRetry(Box::new(err))),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Replays every pending batch and flushes the inner sink, failing if the outbox could not be emptied.
+    async fn flush(&self) -> Result<(), SinkError> {
+        let mut outbox = self.outbox.lock().await;
+        self.replay(&mut outbox, None).await?;
+        self.inner.flush().await
+    }
+
+    fn set_schema_hashes(&self, schema_hashes: &HashMap<String, String>) {
+        self.inner.set_schema_hashes(schema_hashes);
+    }
+
+    fn set_partitioner(&self, partitioner: Arc<dyn Partitioner>) {
+        self.inner.set_partitioner(partitioner);
+    }
+
+    async fn reinitialize(&self) -> Result<(), SinkError> {
+        self.inner.reinitialize().await?;
+        self.outbox.lock().await.retry_at = None;
+        Ok(())
+    }
+}
+
+// an aborted batch can be replayed later, unless it is too large to ever be sent
+fn is_transient(err: &SinkError) -> bool {
+    err.is_aborted() && !matches!(err, SinkError::Producer(ProducerError::Oversized(_)))
+}
+
+fn encode_batch(records: &[Record]) -> Vec<u8> {
+    let stored: Vec<StoredRecord> = records.iter()
+        .map(|r| StoredRecord {
+            topic: r.topic.clone(),
+            key: hex::encode(&r.key),
+            payload: hex::encode(&r.payload),
+            encoding: r.encoding.to_string(),
+            proto_name: r.proto_name.clone(),
+            headers: r.headers.iter().map(|(k, v)| (k.to_string(), hex::encode(v))).collect(),
+            timestamp: r.timestamp,
+            event_time: r.event_time,
+            entity: r.entity.as_ref().map(hex::encode),
+        })
+        .collect();
+
+    let mut line = serde_json::to_vec(&stored).expect("failed to serialize outbox batch");
+    line.push(b'\n');
+    line
+}
+
+fn decode_batch(line: &[u8]) -> Option<Vec<Record>> {
+    let stored: Vec<StoredRecord> = serde_json::from_slice(line).ok()?;
+    stored.into_iter()
+        .map(|s| {
+            let mut headers = Headers::new();
+            for (k, v) in s.headers {
+                headers.insert(&k, hex::decode(v).ok()?);
+            }
+            Some(Record {
+                topic: s.topic,
+                key: hex::decode(s.key).ok()?,
+                payload: hex::decode(s.payload).ok()?,
+                encoding: s.encoding.parse().ok()?,
+                proto_name: s.proto_name,
+                descriptor: None,
+                headers,
+                timestamp: s.timestamp,
+                event_time: s.event_time,
+                entity: match s.entity {
+                    Some(entity) => Some(hex::decode(entity).ok()?),
+                    None => None,
+                },
+            })
+        })
+        .collect()
+}
+
+// lines of the pending batches, after the replayed offset
+fn load(path: &Path) -> io::Result<VecDeque<Vec<u8>>> {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(VecDeque::new()),
+        Err(err) => return Err(err),
+    };
+    let committed = read_offset(path, &content);
+
+    let mut pending = VecDeque::new();
+    let mut lines = content[committed..].split_inclusive(|b| *b == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        match decode_batch(line) {
+            Some(_) if line.ends_with(b"\n") => pending.push_back(line.to_vec()),
+            // only the last line can be incomplete, if the process died while appending it
+            _ if lines.peek().is_none() => warn!("ignoring incomplete last outbox entry in {:?}", path),
+            _ => {
+                let message = format!("corrupted outbox entry after {} pending batches in {:?}", pending.len(), path);
+                return Err(io::Error::new(ErrorKind::InvalidData, message));
+            }
+        }
+    }
+    Ok(pending)
+}
+
+fn append(mut file: &File, line: &[u8]) -> io::Result<()> {
+    file.write_all(line)?;
+    file.sync_data()
+}
+
+fn offset_path(path: &Path) -> PathBuf {
+    path.with_extension("offset")
+}
+
+// an offset which is missing or not at the start of a line replays the whole log
+fn read_offset(path: &Path, content: &[u8]) -> usize {
+    let offset = match fs::read_to_string(offset_path(path)) {
+        Ok(offset) => offset.trim().parse::<usize>().unwrap_or_default(),
+        Err(_) => 0,
+    };
+    match offset {
+        0 => 0,
+        offset if offset >= content.len() => content.len(),
+        offset if content[offset - 1] == b'\n' => offset,
+        offset => {
+            warn!("ignoring invalid outbox offset {} of {:?}", offset, path);
+            0
+        }
+    }
+}
+
+fn write_offset(path: &Path, offset: u64) -> io::Result<()> {
+    fs::write(offset_path(path), offset.to_string())
+}
+
+fn rewrite(path: &Path, pending: &VecDeque<Vec<u8>>) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    for line in pending {
+        file.write_all(line)?;
+    }
+    file.sync_data()?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use crate::kafka_utils::proto_test::utils;
+    use crate::sink::MemorySink;
+
+    use super::*;
+
+    fn build_record(key: &str) -> Record {
+        let mut record = utils::build_record(key, vec![8, 1]);
+        record.headers.insert("trace-id", vec![0, 1, 2]);
+        record.timestamp = Some(1_672_531_200_000);
+        record
+    }
+
+    fn outbox_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join("nakji-connector-test").join(name);
+        remove(&path);
+        path
+    }
+
+    fn remove(path: &Path) {
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(offset_path(path));
+    }
+
+    #[tokio::test]
+    async fn replays_in_order_after_recovery() {
+        let path = outbox_path("replay.outbox.log");
+        let inner = MemorySink::new();
+        let outbox = OutboxSink::open(&path, OUTBOX_MAX_BYTES, Box::new(inner.clone())).unwrap();
+
+        inner.abort_next(2);
+        outbox.write(vec![build_record("ethereum.1")]).await.unwrap();
+        outbox.write(vec![build_record("ethereum.2")]).await.unwrap();
+        assert!(outbox.flush().await.is_err());
+        assert_eq!(outbox.metrics().await.pending_batches, 2);
+        assert!(inner.records().is_empty());
+
+        outbox.flush().await.unwrap();
+
+        assert_eq!(inner.transactions(), vec![vec![build_record("ethereum.1")], vec![build_record("ethereum.2")]]);
+        let metrics = outbox.metrics().await;
+        assert_eq!((metrics.pending_batches, metrics.pending_bytes, metrics.appended_batches, metrics.replayed_batches), (0, 0, 2, 2));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        remove(&path);
+    }
+
+    #[tokio::test]
+    async fn pending_batches_survive_restart() {
+        let path = outbox_path("restart.outbox.log");
+        let inner = MemorySink::new();
+        inner.abort_next(1);
+        let outbox = OutboxSink::open(&path, OUTBOX_MAX_BYTES, Box::new(inner.clone())).unwrap();
+        outbox.write(vec![build_record("ethereum.1"), build_record("ethereum.2")]).await.unwrap();
+        drop(outbox);
+        // a torn write of the previous process
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"[{\"topic\":").unwrap();
+
+        let inner = MemorySink::new();
+        let outbox = OutboxSink::open(&path, OUTBOX_MAX_BYTES, Box::new(inner.clone())).unwrap();
+        assert_eq!(outbox.metrics().await.pending_batches, 1);
+        outbox.flush().await.unwrap();
+
+        assert_eq!(inner.transactions(), vec![vec![build_record("ethereum.1"), build_record("ethereum.2")]]);
+
+        remove(&path);
+    }
+
+    #[tokio::test]
+    async fn rejects_batches_over_size_limit() {
+        let path = outbox_path("full.outbox.log");
+        let inner = MemorySink::new();
+        inner.abort_next(1);
+        let size = encode_batch(&[build_record("ethereum.1")]).len() as u64;
+        let outbox = OutboxSink::open(&path, size, Box::new(inner)).unwrap();
+
+        outbox.write(vec![build_record("ethereum.1")]).await.unwrap();
+        let err = outbox.write(vec![build_record("ethereum.2")]).await.unwrap_err();
+
+        assert!(matches!(err, SinkError::OutboxFull { .. }));
+        assert_eq!(outbox.metrics().await.pending_batches, 1);
+
+        remove(&path);
+    }
+
+    #[tokio::test]
+    async fn resumes_after_replayed_offset() {
+        let path = outbox_path("offset.outbox.log");
+        let inner = MemorySink::new();
+        inner.abort_next(1);
+        let outbox = OutboxSink::open(&path, OUTBOX_MAX_BYTES, Box::new(inner)).unwrap();
+        for key in ["ethereum.1", "ethereum.2", "ethereum.3"] {
+            outbox.write(vec![build_record(key)]).await.unwrap();
+        }
+
+        outbox.outbox.lock().await.commit(1).await.unwrap();
+        let offset = encode_batch(&[build_record("ethereum.1")]).len();
+        assert_eq!(fs::read_to_string(offset_path(&path)).unwrap(), offset.to_string());
+        drop(outbox);
+
+        let inner = MemorySink::new();
+        let outbox = OutboxSink::open(&path, OUTBOX_MAX_BYTES, Box::new(inner.clone())).unwrap();
+        assert_eq!(outbox.metrics().await.pending_batches, 2);
+        outbox.flush().await.unwrap();
+
+        assert_eq!(inner.transactions(), vec![vec![build_record("ethereum.2")], vec![build_record("ethereum.3")]]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        assert_eq!(fs::read_to_string(offset_path(&path)).unwrap(), "0");
+
+        remove(&path);
+    }
+
+    #[tokio::test]
+    async fn fatal_replay_error_is_returned_until_batch_is_skipped() {
+        let path = outbox_path("fatal.outbox.log");
+        let inner = MemorySink::new();
+        let outbox = OutboxSink::open(&path, OUTBOX_MAX_BYTES, Box::new(inner.clone())).unwrap();
+
+        inner.fence();
+        let err = outbox.write(vec![build_record("ethereum.1")]).await.unwrap_err();
+        assert!(matches!(&err, SinkError::KeptForRetry(source) if matches!(**source, SinkError::Producer(ProducerError::Fenced))));
+        assert!(!err.is_aborted());
+        assert_eq!(outbox.metrics().await.pending_batches, 1);
+
+        inner.unfence();
+        assert_eq!(outbox.skip_batch().await.unwrap(), Some(vec![build_record("ethereum.1")]));
+        assert_eq!(outbox.skip_batch().await.unwrap(), None);
+        outbox.write(vec![build_record("ethereum.2")]).await.unwrap();
+
+        assert_eq!(inner.transactions(), vec![vec![build_record("ethereum.2")]]);
+        let metrics = outbox.metrics().await;
+        assert_eq!((metrics.pending_batches, metrics.replayed_batches, metrics.skipped_batches), (0, 1, 1));
+
+        remove(&path);
+    }
+
+    #[tokio::test]
+    async fn fails_to_open_with_corrupted_entry_before_the_last() {
+        let path = outbox_path("corrupted.outbox.log");
+        let mut content = encode_batch(&[build_record("ethereum.1")]);
+        content.extend_from_slice(b"[{\"topic\":\n");
+        content.extend(encode_batch(&[build_record("ethereum.2")]));
+        fs::write(&path, content).unwrap();
+
+        let err = OutboxSink::open(&path, OUTBOX_MAX_BYTES, Box::new(MemorySink::new())).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        remove(&path);
+    }
+}
@@ -0,0 +1,33 @@
+use std::io::{self, Write};
+
+use async_trait::async_trait;
+
+use crate::kafka_utils::Record;
+
+use super::{record_to_json, Sink, SinkError};
+
+/// Prints every record as pretty JSON, for running a connector locally without Kafka.
+#[derive(Default)]
+pub struct StdoutSink;
+
+impl StdoutSink {
+    pub fn new() -> Self {
+        StdoutSink
+    }
+}
+
+#[async_trait]
+impl Sink for StdoutSink {
+    async fn write(&self, records: Vec<Record>) -> Result<(), SinkError> {
+        let mut stdout = io::stdout().lock();
+        for record in &records {
+            let json = serde_json::to_string_pretty(&record_to_json(record)).expect("failed to serialize record");
+            writeln!(stdout, "{}", json)?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), SinkError> {
+        Ok(io::stdout().flush()?)
+    }
+}